@@ -0,0 +1,106 @@
+use crate::job::LOG;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// An `--answer` rule: when a host's output stops at `prompt`, type
+/// `response` into its window
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Answer {
+    pub prompt: String,
+    pub response: String,
+}
+
+impl FromStr for Answer {
+    type Err = String;
+
+    /// Parse `PROMPT=RESPONSE`, splitting at the last `=` as prompts are
+    /// more likely to have one than responses
+    fn from_str(text: &str) -> Result<Answer, String> {
+        match text.rsplit_once('=') {
+            Some((prompt, response)) if !prompt.trim().is_empty() => Ok(Answer {
+                prompt: prompt.into(),
+                response: response.into(),
+            }),
+            _ => Err(format!("expected PROMPT=RESPONSE, found '{}'", text)),
+        }
+    }
+}
+
+/// How much of the end of a log to look at for a prompt
+const TAIL_BYTES: u64 = 1024;
+
+/// Watches hosts' output for prompts to answer
+#[derive(Debug, Default)]
+pub struct Answerer {
+    answers: Vec<Answer>,
+    /// size of each host's log when we last answered it, so a prompt is
+    /// answered once even if the answer isn't echoed
+    answered: HashMap<PathBuf, u64>,
+}
+
+impl Answerer {
+    pub fn new(answers: Vec<Answer>) -> Answerer {
+        Answerer {
+            answers,
+            answered: HashMap::new(),
+        }
+    }
+
+    /// The response to type for the host in `host_dir`, if its output has
+    /// stopped at a prompt we answer which we haven't answered yet
+    pub fn check(&mut self, host_dir: &Path) -> Option<&str> {
+        if self.answers.is_empty() {
+            return None;
+        }
+        let (len, tail) = read_tail(&host_dir.join(LOG))?;
+        if self.answered.get(host_dir) == Some(&len) {
+            return None;
+        }
+        let answer = self
+            .answers
+            .iter()
+            .find(|answer| at_prompt(&tail, &answer.prompt))?;
+        self.answered.insert(host_dir.into(), len);
+        Some(&answer.response)
+    }
+}
+
+/// Whether output ends with `prompt` on its last, unfinished line
+fn at_prompt(output: &str, prompt: &str) -> bool {
+    let last = output.rsplit('\n').next().unwrap_or_default();
+    last.trim_end().ends_with(prompt.trim_end())
+}
+
+/// The size of a log and the text at its end
+fn read_tail(log: &Path) -> Option<(u64, String)> {
+    let mut file = File::open(log).ok()?;
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL_BYTES)))
+        .ok()?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail).ok()?;
+    Some((len, String::from_utf8_lossy(&tail).into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_at_prompt() {
+        let answer: Answer = "Proceed? [y/N]=y".parse().unwrap();
+        assert_eq!(answer.prompt, "Proceed? [y/N]");
+        assert_eq!(answer.response, "y");
+        assert!("key=value=".parse::<Answer>().is_ok());
+        assert!("=y".parse::<Answer>().is_err());
+
+        let prompt = &answer.prompt;
+        assert!(at_prompt("Installing\r\nProceed? [y/N] ", prompt));
+        assert!(!at_prompt("Proceed? [y/N] y\r\n", prompt));
+        assert!(!at_prompt("Proceed? [y/N] y\r\nDone", prompt));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::hosts::{valid_tag, Host, HostsError};
+use serde_json::Value;
+use std::process::Command;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, HostsError>;
+
+/// A query for running EC2 instances, from an `@aws:` host spec item such as
+/// `@aws:tag:Role=web+dns=public`
+#[derive(Debug, PartialEq)]
+pub struct Ec2Query {
+    /// EC2 filters as name and value, e.g. `tag:Role` and `web`
+    filters: Vec<(String, String)>,
+
+    /// what to use as each instance's host name
+    address: Address,
+}
+
+/// Which of an instance's names becomes its host name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Address {
+    PrivateDns,
+    PublicDns,
+    /// the instance id, for `--backend ssm`
+    Id,
+}
+
+impl Address {
+    fn field(&self) -> &'static str {
+        match self {
+            Address::PrivateDns => "PrivateDnsName",
+            Address::PublicDns => "PublicDnsName",
+            Address::Id => "InstanceId",
+        }
+    }
+}
+
+impl FromStr for Ec2Query {
+    type Err = HostsError;
+
+    /// Parse `+` separated `name=value` EC2 filters. The pseudo filter
+    /// `dns=public` (or `dns=private`, the default) picks the DNS name to use,
+    /// and `dns=id` uses instance ids instead, for SSM.
+    fn from_str(query: &str) -> Result<Ec2Query> {
+        let mut filters = vec![];
+        let mut address = Address::PrivateDns;
+        for filter in query.split('+') {
+            let (name, value) = filter
+                .split_once('=')
+                .filter(|(name, value)| !name.is_empty() && !value.is_empty())
+                .ok_or_else(|| {
+                    HostsError::InvalidHost(format!("expected @aws:name=value, found '{}'", filter))
+                })?;
+            match (name, value) {
+                ("dns", "public") => address = Address::PublicDns,
+                ("dns", "private") => address = Address::PrivateDns,
+                ("dns", "id") => address = Address::Id,
+                ("dns", _) => {
+                    return Err(HostsError::InvalidHost(format!(
+                        "dns must be public, private or id, found '{}'",
+                        value
+                    )))
+                }
+                _ => filters.push((name.into(), value.into())),
+            }
+        }
+        Ok(Ec2Query { filters, address })
+    }
+}
+
+impl Ec2Query {
+    pub fn describe(&self) -> String {
+        let filters: Vec<String> = self
+            .filters
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        format!(
+            "running EC2 instances matching {} by {}",
+            filters.join(" and "),
+            match self.address {
+                Address::PrivateDns => "private DNS name",
+                Address::PublicDns => "public DNS name",
+                Address::Id => "instance id",
+            }
+        )
+    }
+
+    /// Look up matching running instances with the aws cli, which takes care
+    /// of credentials and region from the environment.
+    pub fn resolve(&self) -> Result<Vec<Host>> {
+        let mut cmd = Command::new("aws");
+        cmd.args(["ec2", "describe-instances", "--output", "json", "--filters"]);
+        cmd.arg("Name=instance-state-name,Values=running");
+        for (name, value) in &self.filters {
+            cmd.arg(format!("Name={},Values={}", name, value));
+        }
+        let output = cmd.output().map_err(|err| HostsError::Source {
+            source_name: "aws".into(),
+            msg: format!("unable to run aws cli: {}", err),
+        })?;
+        if !output.status.success() {
+            return Err(HostsError::Source {
+                source_name: "aws".into(),
+                msg: String::from_utf8_lossy(&output.stderr).trim().into(),
+            });
+        }
+        hosts_from_json(&String::from_utf8_lossy(&output.stdout), self.address)
+    }
+}
+
+/// Turn `describe-instances` output into hosts, with each instance's tags
+/// mapped to `Key=Value` bdsh tags.
+fn hosts_from_json(json: &str, address: Address) -> Result<Vec<Host>> {
+    let bad_json = |msg: String| HostsError::Source {
+        source_name: "aws".into(),
+        msg,
+    };
+    let data: Value =
+        serde_json::from_str(json).map_err(|err| bad_json(format!("bad json: {}", err)))?;
+    let field = address.field();
+
+    let mut hosts = vec![];
+    let instances = data["Reservations"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|r| r["Instances"].as_array().into_iter().flatten());
+    for instance in instances {
+        let name = match instance[field].as_str() {
+            Some(name) if !name.is_empty() => name,
+            // e.g. no public name, nothing to connect to
+            _ => continue,
+        };
+        let tags = instance["Tags"]
+            .as_array()
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(|tag| {
+                        Some(format!(
+                            "{}={}",
+                            tag["Key"].as_str()?,
+                            tag["Value"].as_str()?
+                        ))
+                    })
+                    .filter(|tag| valid_tag(tag))
+                    .collect()
+            })
+            .unwrap_or_default();
+        hosts.push(Host {
+            name: name.into(),
+            tags,
+            ..Default::default()
+        });
+    }
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hosts_from_json() {
+        let json = r#"{"Reservations": [{"Instances": [
+            {"InstanceId": "i-0abc", "PrivateDnsName": "ip-10-0-0-1.ec2.internal", "PublicDnsName": "",
+             "Tags": [{"Key": "Role", "Value": "web"}, {"Key": "Name", "Value": "web 1"}]},
+            {"PrivateDnsName": "ip-10-0-0-2.ec2.internal",
+             "PublicDnsName": "ec2-1-2-3-4.compute-1.amazonaws.com"}
+        ]}]}"#;
+        let hosts = hosts_from_json(json, Address::PrivateDns).unwrap();
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].name, "ip-10-0-0-1.ec2.internal");
+        assert_eq!(hosts[0].tags, vec!["Role=web"]);
+
+        let hosts = hosts_from_json(json, Address::PublicDns).unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].name, "ec2-1-2-3-4.compute-1.amazonaws.com");
+
+        let hosts = hosts_from_json(json, Address::Id).unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].name, "i-0abc");
+
+        let query: Ec2Query = "tag:Role=web+dns=public".parse().unwrap();
+        assert_eq!(query.filters, vec![("tag:Role".into(), "web".into())]);
+        assert_eq!(query.address, Address::PublicDns);
+        assert!("tag:Role".parse::<Ec2Query>().is_err());
+    }
+}
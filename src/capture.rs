@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+/// Copy `input` to `output` as it arrives, and to `log`, keeping only the
+/// first and last `max_bytes / 2` bytes in the log with a note of how much
+/// was dropped between them. Returns the number of bytes dropped.
+pub fn capture(
+    mut input: impl Read,
+    mut output: impl Write,
+    mut log: impl Write,
+    max_bytes: u64,
+) -> io::Result<u64> {
+    let head_bytes = max_bytes / 2;
+    let tail_bytes = (max_bytes - head_bytes) as usize;
+    let mut written = 0;
+    let mut tail = VecDeque::new();
+    let mut dropped = 0;
+    let mut buf = [0; 8192];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let data = &buf[..n];
+        // flush each chunk, so prompts without a newline show up
+        output.write_all(data)?;
+        output.flush()?;
+
+        let head = data.len().min((head_bytes - written) as usize);
+        log.write_all(&data[..head])?;
+        log.flush()?;
+        written += head as u64;
+        for &byte in &data[head..] {
+            tail.push_back(byte);
+            // with no room for a tail at all, this drops every byte
+            if tail.len() > tail_bytes {
+                tail.pop_front();
+                dropped += 1;
+            }
+        }
+    }
+    if dropped > 0 {
+        write!(log, "\n[bdsh: {} bytes of output dropped]\n", dropped)?;
+    }
+    let (first, second) = tail.as_slices();
+    log.write_all(first)?;
+    log.write_all(second)?;
+    log.flush()?;
+    Ok(dropped)
+}
+
+/// Reads `input` with every occurrence of `secret` replaced by `***`.
+/// Bytes which might start the secret are held back until it's clear they
+/// don't.
+pub struct Scrub<R> {
+    input: R,
+    secret: Vec<u8>,
+    /// read, but maybe part of the secret
+    pending: Vec<u8>,
+    /// scrubbed and ready to be read
+    ready: VecDeque<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Scrub<R> {
+    pub fn new(input: R, secret: &str) -> Scrub<R> {
+        Scrub {
+            input,
+            secret: secret.as_bytes().to_vec(),
+            pending: vec![],
+            ready: VecDeque::new(),
+            eof: false,
+        }
+    }
+
+    /// Move what's pending to ready, scrubbed, holding back a possible start
+    /// of the secret at the end unless the input is done
+    fn scrub(&mut self) {
+        let len = self.secret.len();
+        while let Some(at) = self
+            .pending
+            .windows(len)
+            .position(|window| window == self.secret)
+        {
+            self.ready.extend(self.pending.drain(..at));
+            self.ready.extend(b"***");
+            self.pending.drain(..len);
+        }
+        let keep = if self.eof {
+            0
+        } else {
+            (1..len.min(self.pending.len() + 1))
+                .rev()
+                .find(|&n| {
+                    self.secret
+                        .starts_with(&self.pending[self.pending.len() - n..])
+                })
+                .unwrap_or(0)
+        };
+        let done = self.pending.len() - keep;
+        self.ready.extend(self.pending.drain(..done));
+    }
+}
+
+impl<R: Read> Read for Scrub<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.ready.is_empty() && !self.eof {
+            let mut chunk = [0; 8192];
+            let n = self.input.read(&mut chunk)?;
+            self.eof = n == 0;
+            self.pending.extend(&chunk[..n]);
+            if !self.secret.is_empty() {
+                self.scrub();
+            } else {
+                self.ready.extend(self.pending.drain(..));
+            }
+        }
+        let n = buf.len().min(self.ready.len());
+        for (to, from) in buf.iter_mut().zip(self.ready.drain(..n)) {
+            *to = from;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let input = b"0123456789abcdefghij";
+        let (mut output, mut log) = (vec![], vec![]);
+        let dropped = capture(&input[..], &mut output, &mut log, 8).unwrap();
+        assert_eq!(output, input);
+        assert_eq!(dropped, 12);
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            "0123\n[bdsh: 12 bytes of output dropped]\nghij"
+        );
+
+        let (mut output, mut log) = (vec![], vec![]);
+        assert_eq!(capture(&b"short"[..], &mut output, &mut log, 8).unwrap(), 0);
+        assert_eq!(log, b"short");
+
+        let (mut output, mut log) = (vec![], vec![]);
+        assert_eq!(capture(&input[..], &mut output, &mut log, 0).unwrap(), 20);
+        assert_eq!(log, b"\n[bdsh: 20 bytes of output dropped]\n");
+    }
+
+    #[test]
+    fn test_scrub() {
+        // one byte at a time, so the secret is split across reads
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(1).min(buf.len());
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let input = b"[sudo] hunter2\nhunt hunter2hunter2 hunte";
+        let mut out = String::new();
+        Scrub::new(Trickle(input), "hunter2")
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, "[sudo] ***\nhunt ****** hunte");
+    }
+}
@@ -0,0 +1,293 @@
+use crate::guard::Guard;
+use crate::hosts::glob_regex;
+use crate::job::{Backend, HostKeyPolicy, TagSsh};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+type Result<T> = std::result::Result<T, ConfigError>;
+
+/// Defaults from `config.toml`, each overridden by the matching flag
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub output_dir: Option<PathBuf>,
+    pub backend: Option<Backend>,
+    pub ssh_cmd: Option<String>,
+    /// `KEY=VALUE` options, as for --ssh-opt
+    pub ssh_opts: Vec<String>,
+    pub ssh_args: Vec<String>,
+    pub forward_agent: Option<bool>,
+    pub host_key_policy: Option<HostKeyPolicy>,
+    pub jump: Option<String>,
+    pub compress: Option<bool>,
+    pub bell: Option<bool>,
+    pub askpass: Option<bool>,
+    pub secret_cmd: Option<String>,
+
+    /// ask for the host count to be typed before running on more hosts
+    pub confirm_over: Option<usize>,
+
+    /// template for host window names, as for --window-name
+    pub window_name: Option<String>,
+
+    /// `PROMPT=RESPONSE` prompts to answer, as for --answer
+    pub answers: Vec<String>,
+
+    /// `[[guard]]` rules checked against each command before it runs
+    pub guard: Vec<Guard>,
+
+    /// `[tag.NAME]` sections, with ssh-cmd and ssh-args for hosts tagged
+    /// NAME; a profile's section for a tag replaces the base's
+    pub tag: BTreeMap<String, TagSsh>,
+
+    /// `[profile.NAME]` sections, each with the settings above
+    pub profile: HashMap<String, Config>,
+
+    /// other config files to take settings from, relative to this one;
+    /// settings here override theirs
+    pub include: Vec<String>,
+}
+
+impl Config {
+    /// The settings with profile `name` laid over them
+    pub fn with_profile(mut self, name: &str) -> Result<Config> {
+        let profile = self
+            .profile
+            .remove(name)
+            .ok_or_else(|| ConfigError::UnknownProfile(name.into()))?;
+        if !profile.profile.is_empty() || !profile.include.is_empty() {
+            return Err(ConfigError::NestedProfile(name.into()));
+        }
+        Ok(profile.over(Config {
+            profile: HashMap::new(),
+            ..self
+        }))
+    }
+
+    /// These settings, with `base` filling in any not given. Our ssh
+    /// options and args go first, as ssh keeps the first value it sees.
+    fn over(self, mut base: Config) -> Config {
+        base.profile.extend(self.profile);
+        base.tag.extend(self.tag);
+        Config {
+            output_dir: self.output_dir.or(base.output_dir),
+            backend: self.backend.or(base.backend),
+            ssh_cmd: self.ssh_cmd.or(base.ssh_cmd),
+            ssh_opts: [self.ssh_opts, base.ssh_opts].concat(),
+            ssh_args: [self.ssh_args, base.ssh_args].concat(),
+            forward_agent: self.forward_agent.or(base.forward_agent),
+            host_key_policy: self.host_key_policy.or(base.host_key_policy),
+            jump: self.jump.or(base.jump),
+            compress: self.compress.or(base.compress),
+            bell: self.bell.or(base.bell),
+            askpass: self.askpass.or(base.askpass),
+            secret_cmd: self.secret_cmd.or(base.secret_cmd),
+            confirm_over: self.confirm_over.or(base.confirm_over),
+            window_name: self.window_name.or(base.window_name),
+            answers: [self.answers, base.answers].concat(),
+            guard: [self.guard, base.guard].concat(),
+            tag: base.tag,
+            profile: base.profile,
+            include: vec![],
+        }
+    }
+}
+
+/// bdsh's config directory, `$XDG_CONFIG_HOME/bdsh` falling back to
+/// `~/.config/bdsh`
+pub fn config_dir() -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("bdsh"))
+}
+
+/// Load the config from `path`, or from `config.toml` in the config
+/// directory if not given. A missing default config is just empty.
+pub fn load(path: Option<&Path>) -> Result<Config> {
+    let (path, required) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match config_dir() {
+            Some(dir) => (dir.join("config.toml"), false),
+            None => return Ok(Config::default()),
+        },
+    };
+    match fs::metadata(&path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !required => Ok(Config::default()),
+        _ => load_from(&path, &mut vec![]),
+    }
+}
+
+/// Load a config file and those it includes, later includes overriding
+/// earlier ones. `including` holds the files that led here.
+fn load_from(path: &Path, including: &mut Vec<PathBuf>) -> Result<Config> {
+    let io_err = |source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let contents = fs::read_to_string(path).map_err(io_err)?;
+    let canonical = path.canonicalize().map_err(io_err)?;
+    if including.contains(&canonical) {
+        return Err(ConfigError::IncludeLoop(path.to_path_buf()));
+    }
+    let config: Config = toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    including.push(canonical);
+    let mut base = Config::default();
+    for pattern in &config.include {
+        for included in include_paths(path, pattern).map_err(io_err)? {
+            base = load_from(&included, including)?.over(base);
+        }
+    }
+    including.pop();
+    Ok(config.over(base))
+}
+
+/// The files an include of `pattern` in the file at `from` refers to.
+/// Relative patterns are relative to `from`'s directory, and the file name
+/// may contain `*` and `?`, matching files in sorted order.
+pub fn include_paths(from: &Path, pattern: &str) -> io::Result<Vec<PathBuf>> {
+    let path = from.parent().unwrap_or(Path::new(".")).join(pattern);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![path]);
+    }
+    let glob = glob_regex(&name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if glob.is_match(&entry.file_name().to_string_lossy()) && entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("unable to read {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("invalid config in {path}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("no such profile in config: {0}")]
+    UnknownProfile(String),
+
+    #[error("profile {0} may not contain profiles or includes")]
+    NestedProfile(String),
+
+    #[error("{0} includes itself")]
+    IncludeLoop(PathBuf),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+            output-dir = "/var/tmp/bdsh"
+            ssh-opts = ["ConnectTimeout=5"]
+            host-key-policy = "accept-new"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.output_dir, Some(PathBuf::from("/var/tmp/bdsh")));
+        assert_eq!(config.ssh_opts, vec!["ConnectTimeout=5"]);
+        assert_eq!(config.host_key_policy, Some(HostKeyPolicy::AcceptNew));
+        assert_eq!(config.backend, None);
+
+        assert!(toml::from_str::<Config>("parallelism = 4").is_err());
+    }
+
+    #[test]
+    fn test_with_profile() {
+        let config: Config = toml::from_str(
+            r#"
+            ssh-opts = ["ConnectTimeout=5"]
+            jump = "bastion"
+
+            [profile.prod]
+            ssh-opts = ["User=deploy"]
+            host-key-policy = "strict"
+            "#,
+        )
+        .unwrap();
+        let prod = config.with_profile("prod").unwrap();
+        assert_eq!(prod.ssh_opts, vec!["User=deploy", "ConnectTimeout=5"]);
+        assert_eq!(prod.host_key_policy, Some(HostKeyPolicy::Strict));
+        assert_eq!(prod.jump.as_deref(), Some("bastion"));
+
+        // a profile can turn off what the base turns on
+        let config: Config = toml::from_str(
+            r#"
+            compress = true
+            bell = true
+
+            [profile.quiet]
+            compress = false
+            "#,
+        )
+        .unwrap();
+        let quiet = config.with_profile("quiet").unwrap();
+        assert_eq!(quiet.compress, Some(false));
+        assert_eq!(quiet.bell, Some(true));
+
+        let config: Config = toml::from_str(
+            r#"
+            [tag.teleport]
+            ssh-cmd = "tsh ssh"
+
+            [profile.prod.tag.teleport]
+            ssh-args = ["-o", "User=deploy"]
+            "#,
+        )
+        .unwrap();
+        let prod = config.with_profile("prod").unwrap();
+        assert_eq!(prod.tag["teleport"].ssh_args, vec!["-o", "User=deploy"]);
+        assert_eq!(prod.tag["teleport"].ssh_cmd, None);
+
+        let config: Config = toml::from_str("[profile.prod]").unwrap();
+        assert!(config.with_profile("staging").is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = env::temp_dir().join(format!("bdsh-config-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("teams")).unwrap();
+        fs::write(
+            dir.join("config.toml"),
+            "include = [\"teams/*.toml\"]\njump = \"bastion\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("teams/a.toml"), "jump = \"a\"\nssh-cmd = \"a\"\n").unwrap();
+        fs::write(dir.join("teams/b.toml"), "ssh-cmd = \"b\"\n").unwrap();
+        fs::write(dir.join("teams/notes.txt"), "not config").unwrap();
+
+        let config = load(Some(&dir.join("config.toml"))).unwrap();
+        assert_eq!(config.jump.as_deref(), Some("bastion"));
+        assert_eq!(config.ssh_cmd.as_deref(), Some("b"));
+
+        fs::write(dir.join("teams/b.toml"), "include = [\"../config.toml\"]\n").unwrap();
+        assert!(load(Some(&dir.join("config.toml"))).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+use crate::hosts::{Host, HostsError};
+use std::process::Command;
+
+type Result<T> = std::result::Result<T, HostsError>;
+
+/// Local container engines, which share a cli
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    Docker,
+    Podman,
+}
+
+impl Engine {
+    pub fn cli(&self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+        }
+    }
+
+    /// Running containers matching all of `filters`, as for `docker ps
+    /// --filter`, for use with `--backend docker` or `--backend podman`
+    pub fn containers(&self, filters: &[String]) -> Result<Vec<Host>> {
+        let mut cmd = Command::new(self.cli());
+        cmd.args(["ps", "--format", "{{.Names}}"]);
+        for filter in filters {
+            cmd.args(["--filter", filter]);
+        }
+        let output = cmd.output().map_err(|err| HostsError::Source {
+            source_name: self.cli().into(),
+            msg: format!("unable to run {}: {}", self.cli(), err),
+        })?;
+        if !output.status.success() {
+            return Err(HostsError::Source {
+                source_name: self.cli().into(),
+                msg: String::from_utf8_lossy(&output.stderr).trim().into(),
+            });
+        }
+        Ok(hosts_from_names(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+/// Turn container names, one per line, into hosts
+fn hosts_from_names(names: &str) -> Vec<Host> {
+    names
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| Host {
+            name: line.into(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hosts_from_names() {
+        let hosts = hosts_from_names("app_web_1\napp_web_2\n");
+        let names: Vec<&str> = hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, vec!["app_web_1", "app_web_2"]);
+    }
+}
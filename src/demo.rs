@@ -0,0 +1,33 @@
+use crate::hosts::Host;
+
+/// What each fake host runs, with the `local` backend. Hosts differ in
+/// their output, and some prompt for input, fail, or draw a progress bar.
+pub const SCRIPT: &str = r#"n=${BDSH_HOST#demo-}
+echo "Linux $BDSH_HOST 6.1.0-$((17 + n % 2))-amd64 x86_64 GNU/Linux"
+echo "up $((n * 3)) days, load average: 0.0$n"
+case $((n % 4)) in
+  1) i=0
+     while [ $i -le 20 ]; do
+       printf '\rupgrading [%-20s] %3d%%' "$(printf "%${i}s" | tr ' ' '#')" $((i * 5))
+       i=$((i + 1))
+       sleep 0.2
+     done
+     echo ;;
+  2) printf 'restart nginx? [y/N] '
+     read answer
+     echo "answered '$answer'" ;;
+  3) echo "error: /var is 100% full" >&2
+     exit 1 ;;
+esac
+echo "all done on $BDSH_HOST"
+"#;
+
+/// Fake hosts `demo-1` to `demo-N`
+pub fn hosts(count: usize) -> Vec<Host> {
+    (1..=count)
+        .map(|n| Host {
+            name: format!("demo-{}", n),
+            ..Default::default()
+        })
+        .collect()
+}
@@ -0,0 +1,96 @@
+use crate::run::{has_log, read_log};
+use similar::{ChangeTag, TextDiff};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How a host's output differs between two runs
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Same,
+    /// lines added and removed, and a unified diff
+    Changed {
+        added: usize,
+        removed: usize,
+        diff: String,
+    },
+    OnlyInFirst,
+    OnlyInSecond,
+}
+
+/// Compare each host's output in run directories `first` and `second`,
+/// by host name
+pub fn diff_runs(first: &Path, second: &Path) -> io::Result<Vec<(String, Change)>> {
+    let mut hosts = logged_hosts(first)?;
+    hosts.extend(logged_hosts(second)?);
+    let mut changes = vec![];
+    for host in hosts {
+        let host_dir = |dir: &Path| dir.join(&host);
+        let read = |dir: &Path| {
+            if has_log(&host_dir(dir)) {
+                read_log(&host_dir(dir)).map(Some)
+            } else {
+                Ok(None)
+            }
+        };
+        let change = match (read(first)?, read(second)?) {
+            (Some(old), Some(new)) => compare(
+                (&host_dir(first).to_string_lossy(), &old),
+                (&host_dir(second).to_string_lossy(), &new),
+            ),
+            (Some(_), None) => Change::OnlyInFirst,
+            _ => Change::OnlyInSecond,
+        };
+        changes.push((host, change));
+    }
+    Ok(changes)
+}
+
+/// Hosts with output in run directory `dir`
+fn logged_hosts(dir: &Path) -> io::Result<BTreeSet<String>> {
+    let mut hosts = BTreeSet::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if has_log(&entry.path()) {
+            hosts.insert(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    Ok(hosts)
+}
+
+/// Compare two logs, each given as its name and contents
+fn compare((old_name, old): (&str, &str), (new_name, new): (&str, &str)) -> Change {
+    if old == new {
+        return Change::Same;
+    }
+    let diff = TextDiff::from_lines(old, new);
+    let count = |tag| diff.iter_all_changes().filter(|c| c.tag() == tag).count();
+    Change::Changed {
+        added: count(ChangeTag::Insert),
+        removed: count(ChangeTag::Delete),
+        diff: diff.unified_diff().header(old_name, new_name).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(compare(("x", "a\nb\n"), ("y", "a\nb\n")), Change::Same);
+        match compare(("x/web1", "a\nb\n"), ("y/web1", "a\nc\nd\n")) {
+            Change::Changed {
+                added,
+                removed,
+                diff,
+            } => {
+                assert_eq!((added, removed), (2, 1));
+                assert!(diff.starts_with("--- x/web1\n+++ y/web1\n"));
+                assert!(diff.contains("-b\n+c\n+d\n"));
+            }
+            change => panic!("expected a change, got {:?}", change),
+        }
+    }
+}
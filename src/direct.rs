@@ -0,0 +1,243 @@
+use crate::capture::{capture, Scrub};
+use crate::job::{Job, COMPRESSED_LOG, LOG};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::process::{Child, Command};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How a run without tmux ended
+#[derive(Debug)]
+pub struct Finished {
+    /// each job's exit status, missing if it was killed by a signal
+    pub statuses: Vec<Option<i32>>,
+
+    /// the deadline passed and unfinished jobs were killed
+    pub timed_out: bool,
+
+    /// too many jobs failed and unfinished jobs were killed
+    pub cancelled: bool,
+}
+
+/// Something happening to a job, for `run`'s caller
+pub enum Event {
+    /// a line of output, without its newline
+    Line(String),
+    /// the job is being killed, as the deadline passed before it finished
+    TimedOut,
+    /// the job is being killed, as too many others failed
+    Cancelled,
+    /// the job finished, with its exit status
+    Done(Option<i32>),
+}
+
+/// How long killed jobs get to exit once sent SIGTERM, before SIGKILL
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+enum Message {
+    Line(usize, String),
+    Eof(usize, io::Result<()>),
+}
+
+/// Run every job at once as a child process rather than in a tmux window,
+/// writing the same out.log, and the same files from the job's script.
+/// `on_event` is called with a job's index for each event, with lines of
+/// output only if `lines`. Jobs still running at `deadline`, or once
+/// `cancel_after` jobs have failed, are killed, with SIGKILL if SIGTERM
+/// hasn't done it within `KILL_GRACE`.
+pub fn run(
+    jobs: &[Job],
+    max_output_bytes: Option<u64>,
+    compress: bool,
+    deadline: Option<Instant>,
+    cancel_after: Option<usize>,
+    lines: bool,
+    mut on_event: impl FnMut(usize, Event),
+) -> io::Result<Finished> {
+    let (tx, rx) = mpsc::channel();
+    let mut children: Vec<Option<Child>> = vec![];
+    for (index, job) in jobs.iter().enumerate() {
+        let mut child = job.command().spawn()?;
+        let stdout = child.stdout.take().expect("job stdout is piped");
+        let stdout = Scrub::new(stdout, job.transport.secret().unwrap_or_default());
+        let log_path = job.root.join(LOG);
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let mut sender = LineSender {
+                index,
+                tx: lines.then(|| tx.clone()),
+                partial: vec![],
+            };
+            let result = File::create(&log_path).and_then(|log| {
+                capture(
+                    stdout,
+                    &mut sender,
+                    log,
+                    max_output_bytes.unwrap_or(u64::MAX),
+                )?;
+                sender.finish();
+                if compress {
+                    compress_log(&log_path)?;
+                }
+                Ok(())
+            });
+            let _ = tx.send(Message::Eof(index, result));
+        });
+        children.push(Some(child));
+    }
+    drop(tx);
+
+    let mut finished = Finished {
+        statuses: vec![None; jobs.len()],
+        timed_out: false,
+        cancelled: false,
+    };
+    let mut running = jobs.len();
+    let mut failed = 0;
+    // when jobs were first sent SIGTERM, until they're sent SIGKILL
+    let mut terminated: Option<Instant> = None;
+    while running > 0 {
+        if !finished.timed_out && deadline.is_some_and(|d| Instant::now() > d) {
+            finished.timed_out = true;
+            terminated.get_or_insert_with(Instant::now);
+            for (index, child) in children.iter().enumerate() {
+                if let Some(child) = child {
+                    on_event(index, Event::TimedOut);
+                    kill_group(child, "TERM");
+                }
+            }
+        }
+        if terminated.is_some_and(|at| at.elapsed() >= KILL_GRACE) {
+            terminated = None;
+            for child in children.iter().flatten() {
+                kill_group(child, "KILL");
+            }
+        }
+        let (index, result) = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(Message::Line(index, line)) => {
+                on_event(index, Event::Line(line));
+                continue;
+            }
+            Ok(Message::Eof(index, result)) => (index, result),
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        running -= 1;
+        let status = match children[index].take() {
+            Some(mut child) => child.wait()?.code(),
+            None => None,
+        };
+        if let Err(err) = result {
+            eprintln!(
+                "warning: unable to save output of {}: {}",
+                jobs[index].host.name, err
+            );
+        }
+        finished.statuses[index] = status;
+        on_event(index, Event::Done(status));
+        if status != Some(0) {
+            failed += 1;
+        }
+        if !finished.cancelled && cancel_after.is_some_and(|max| failed >= max) {
+            finished.cancelled = true;
+            terminated.get_or_insert_with(Instant::now);
+            for (index, child) in children.iter().enumerate() {
+                if let Some(child) = child {
+                    on_event(index, Event::Cancelled);
+                    kill_group(child, "TERM");
+                }
+            }
+        }
+    }
+    Ok(finished)
+}
+
+/// Sends each complete line written to it, if it has somewhere to send them
+struct LineSender {
+    index: usize,
+    tx: Option<mpsc::Sender<Message>>,
+    partial: Vec<u8>,
+}
+
+impl LineSender {
+    fn send(&self, line: &[u8]) {
+        if let Some(tx) = &self.tx {
+            let line = String::from_utf8_lossy(line);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            let _ = tx.send(Message::Line(self.index, line.into()));
+        }
+    }
+
+    /// Send whatever is left after the last newline
+    fn finish(&mut self) {
+        if !self.partial.is_empty() {
+            self.send(&self.partial);
+            self.partial.clear();
+        }
+    }
+}
+
+impl Write for LineSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.tx.is_none() {
+            return Ok(buf.len());
+        }
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            self.send(&line[..end]);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Send `signal` to a job's whole process group, so ssh and anything else
+/// the script started goes too
+fn kill_group(child: &Child, signal: &str) {
+    let _ = Command::new("kill")
+        .args(["-s", signal, "--", &format!("-{}", child.id())])
+        .status();
+}
+
+/// Replace out.log with out.log.gz
+fn compress_log(log_path: &std::path::Path) -> io::Result<()> {
+    let mut log = File::open(log_path)?;
+    let gz_path = log_path.with_file_name(COMPRESSED_LOG);
+    let mut gz = GzEncoder::new(File::create(gz_path)?, Compression::default());
+    io::copy(&mut log, &mut gz)?;
+    gz.finish()?;
+    fs::remove_file(log_path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::job::{Backend, Transport};
+
+    #[test]
+    fn test_kill_ignoring_term() {
+        let dir = std::env::temp_dir().join(format!("bdsh-direct-{}", std::process::id()));
+        let transport = Transport {
+            backend: Backend::Local,
+            ..Default::default()
+        };
+        let host = "h1".parse().unwrap();
+        let job = Job::new(&dir, host, &transport, "trap '' TERM; sleep 30");
+        job.write_script().unwrap();
+
+        let started = Instant::now();
+        let deadline = Some(started + Duration::from_millis(200));
+        let finished = run(&[job], None, false, deadline, None, false, |_, _| {}).unwrap();
+        assert!(finished.timed_out);
+        assert_eq!(finished.statuses, vec![None]);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use crate::hosts::Host;
+use regex::Regex;
+use serde::Deserialize;
+use thiserror::Error;
+
+type Result<T> = std::result::Result<T, GuardError>;
+
+/// A `[[guard]]` rule from config: commands matching `pattern` are refused
+/// or need confirming, optionally only when hosts with `tag` are targeted
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Guard {
+    /// regex searched for in the command, as given
+    pub pattern: String,
+    pub action: Action,
+    /// only guard runs on at least one host with this tag
+    pub tag: Option<String>,
+    /// said when the rule stops a command, e.g. why it's dangerous
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// refuse to run the command
+    Block,
+    /// ask before running the command
+    Confirm,
+}
+
+impl Guard {
+    /// What to say when this rule matches
+    pub fn describe(&self) -> String {
+        match &self.message {
+            Some(message) => format!("{} (matches guard '{}')", message, self.pattern),
+            None => format!("command matches guard '{}'", self.pattern),
+        }
+    }
+}
+
+/// The guards `command` on `hosts` trips, blocking ones first
+pub fn check<'a>(guards: &'a [Guard], command: &str, hosts: &[Host]) -> Result<Vec<&'a Guard>> {
+    let mut tripped = vec![];
+    for guard in guards {
+        let pattern = Regex::new(&guard.pattern)
+            .map_err(|err| GuardError::InvalidPattern(guard.pattern.clone(), err.to_string()))?;
+        let targeted = match &guard.tag {
+            Some(tag) => hosts.iter().any(|host| host.tags.contains(tag)),
+            None => true,
+        };
+        if targeted && pattern.is_match(command) {
+            tripped.push(guard);
+        }
+    }
+    tripped.sort_by_key(|guard| guard.action != Action::Block);
+    Ok(tripped)
+}
+
+#[derive(Error, Debug)]
+pub enum GuardError {
+    #[error("invalid guard pattern '{0}': {1}")]
+    InvalidPattern(String, String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let guard = |pattern: &str, action, tag: Option<&str>| Guard {
+            pattern: pattern.into(),
+            action,
+            tag: tag.map(String::from),
+            message: None,
+        };
+        let guards = vec![
+            guard(r"\bshutdown\b", Action::Confirm, None),
+            guard(r"rm\s+-rf\s+/(\s|$)", Action::Block, Some("prod")),
+        ];
+        let prod = Host {
+            name: "db1".into(),
+            tags: vec!["prod".into()],
+            ..Default::default()
+        };
+        let dev = Host {
+            name: "dev1".into(),
+            ..Default::default()
+        };
+
+        let hosts = [dev, prod];
+        let tripped = check(&guards, "rm -rf / ; shutdown -h now", &hosts).unwrap();
+        let actions: Vec<Action> = tripped.iter().map(|guard| guard.action).collect();
+        assert_eq!(actions, vec![Action::Block, Action::Confirm]);
+
+        assert!(check(&guards, "rm -rf /", &hosts[..1]).unwrap().is_empty());
+        assert!(check(&guards, "uptime", &hosts).unwrap().is_empty());
+    }
+}
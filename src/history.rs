@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A run, as recorded in the history file when it starts
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Entry {
+    /// the run's tmux session name
+    pub id: String,
+
+    /// unix seconds
+    pub started: u64,
+
+    pub command: String,
+
+    /// the host spec as given
+    pub hosts: String,
+
+    pub output_dir: PathBuf,
+}
+
+/// Where the history lives, `$XDG_STATE_HOME/bdsh/history.jsonl` falling
+/// back to `~/.local/state/bdsh/history.jsonl`
+fn history_file() -> Option<PathBuf> {
+    let state = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })?;
+    Some(state.join("bdsh").join("history.jsonl"))
+}
+
+/// Append `entry` to the history. Having nowhere to keep it isn't an error.
+pub fn record(entry: &Entry) -> io::Result<()> {
+    let Some(path) = history_file() else {
+        return Ok(());
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let line = serde_json::to_string(entry).map_err(io::Error::other)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+/// Every recorded run, oldest first
+pub fn entries() -> io::Result<Vec<Entry>> {
+    let Some(path) = history_file() else {
+        return Ok(vec![]);
+    };
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(parse(&contents)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(err) => Err(err),
+    }
+}
+
+/// Parse history lines, skipping any that are damaged, e.g. by two runs
+/// appending at once
+fn parse(contents: &str) -> Vec<Entry> {
+    contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+/// How long ago unix time `then` was, roughly, e.g. `5m ago`
+pub fn ago(then: u64) -> String {
+    let secs = now().saturating_sub(then);
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let contents = concat!(
+            r#"{"id":"brave-fox","started":1,"command":"uptime","hosts":":web","output_dir":"/tmp/bdsh-brave-fox"}"#,
+            "\n{\"id\":\"tor\n",
+            r#"{"id":"calm-owl","started":2,"command":"df -h","hosts":"db1","output_dir":"/tmp/x"}"#,
+        );
+        let entries = parse(contents);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, "brave-fox");
+        assert_eq!(entries[1].command, "df -h");
+    }
+}
@@ -0,0 +1,1061 @@
+use crate::aws::Ec2Query;
+use crate::config::{config_dir, include_paths};
+use crate::containers::Engine;
+use crate::kubectl;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+type Result<T> = std::result::Result<T, HostsError>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Host {
+    /// hostname (or address) to connect to
+    pub name: String,
+
+    /// ssh port, if not the default
+    pub port: Option<u16>,
+
+    /// tags from the hosts file, used to select hosts with `:tag`
+    pub tags: Vec<String>,
+
+    /// jump host to reach this host through, as for `ssh -J`
+    pub jump: Option<String>,
+}
+
+impl Host {
+    fn describe(&self) -> String {
+        let mut parts = vec![format!("host {}", self.name)];
+        if let Some(port) = self.port {
+            parts.push(format!("port {}", port));
+        }
+        if !self.tags.is_empty() {
+            parts.push(format!("tagged {}", self.tags.join(", ")));
+        }
+        if let Some(jump) = &self.jump {
+            parts.push(format!("via {}", jump));
+        }
+        parts.join(", ")
+    }
+
+    /// Arguments to pass to ssh to reach this host
+    pub fn ssh_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(jump) = &self.jump {
+            args.push("-J".to_string());
+            args.push(jump.clone());
+        }
+        if let Some(port) = self.port {
+            args.push("-p".to_string());
+            args.push(port.to_string());
+        }
+        args.push(self.name.clone());
+        args
+    }
+}
+
+impl FromStr for Host {
+    type Err = HostsError;
+
+    /// Parse `host`, `host:port`, `[v6addr]:port`, or a bare IPv6 address.
+    fn from_str(spec: &str) -> Result<Host> {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            return Err(HostsError::InvalidHost("empty host".into()));
+        }
+
+        // bracketed address, as in `[::1]:2222`
+        if let Some(rest) = spec.strip_prefix('[') {
+            let (name, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| HostsError::InvalidHost(format!("unclosed '[' in '{}'", spec)))?;
+            let port = match rest {
+                "" => None,
+                _ => match rest.strip_prefix(':') {
+                    Some(port) => Some(parse_port(spec, port)?),
+                    None => {
+                        return Err(HostsError::InvalidHost(format!(
+                            "unexpected '{}' after ']' in '{}'",
+                            rest, spec
+                        )))
+                    }
+                },
+            };
+            return Ok(Host {
+                name: name.into(),
+                port,
+                ..Default::default()
+            });
+        }
+
+        // more than one colon means a bare IPv6 address, which can't carry a port
+        match spec.split_once(':') {
+            Some((name, port)) if !port.contains(':') => Ok(Host {
+                name: name.into(),
+                port: Some(parse_port(spec, port)?),
+                ..Default::default()
+            }),
+            _ => Ok(Host {
+                name: spec.into(),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+fn parse_port(spec: &str, port: &str) -> Result<u16> {
+    port.parse()
+        .map_err(|_| HostsError::InvalidHost(format!("invalid port '{}' in '{}'", port, spec)))
+}
+
+/// One item of a comma separated host spec
+#[derive(Debug, PartialEq)]
+pub enum SpecItem {
+    /// a host, see `Host::from_str`, or several from a pattern such as
+    /// `web[01-20]` (see `expand_pattern`)
+    Hosts(Vec<Host>),
+
+    /// `@path`, the hosts listed in a file
+    File(PathBuf),
+
+    /// `:a:!b`, the hosts in the default hosts file matching a tag filter
+    Tags(TagFilter),
+
+    /// `@aws:filter`, running EC2 instances
+    Aws(Ec2Query),
+
+    /// `@kubectl:selector`, running pods matching a label selector
+    Kubectl(String),
+
+    /// `@docker:filter` or `@podman:filter`, running local containers
+    Containers(Engine, Vec<String>),
+}
+
+impl FromStr for SpecItem {
+    type Err = HostsError;
+
+    fn from_str(item: &str) -> Result<SpecItem> {
+        let item = item.trim();
+        if let Some(query) = item.strip_prefix("@aws:") {
+            Ok(SpecItem::Aws(query.parse()?))
+        } else if let Some(selector) = item.strip_prefix("@kubectl:") {
+            // commas separate spec items, so selector terms are joined with +
+            Ok(SpecItem::Kubectl(selector.replace('+', ",")))
+        } else if let Some(filters) = item.strip_prefix("@docker:") {
+            Ok(SpecItem::Containers(Engine::Docker, split_filters(filters)))
+        } else if let Some(filters) = item.strip_prefix("@podman:") {
+            Ok(SpecItem::Containers(Engine::Podman, split_filters(filters)))
+        } else if let Some(path) = item.strip_prefix('@') {
+            Ok(SpecItem::File(path.into()))
+        } else if item.starts_with(':') {
+            Ok(SpecItem::Tags(parse_tag_filter(item)?))
+        } else {
+            let hosts = expand_pattern(item)?
+                .iter()
+                .map(|name| name.parse())
+                .collect::<Result<_>>()?;
+            Ok(SpecItem::Hosts(hosts))
+        }
+    }
+}
+
+impl SpecItem {
+    fn describe(&self) -> String {
+        match self {
+            SpecItem::Hosts(hosts) => describe_hosts(hosts),
+            SpecItem::File(path) => format!("hosts listed in {}", path.display()),
+            SpecItem::Tags(filter) => format!("hosts file entries {}", filter.describe()),
+            SpecItem::Aws(query) => query.describe(),
+            SpecItem::Kubectl(selector) => format!("running pods matching {}", selector),
+            SpecItem::Containers(engine, filters) => format!(
+                "running {} containers matching {}",
+                engine.cli(),
+                filters.join(" and ")
+            ),
+        }
+    }
+}
+
+fn describe_hosts(hosts: &[Host]) -> String {
+    match hosts {
+        [host] => host.describe(),
+        [first, .., last] if hosts.len() > 4 => {
+            format!("{} hosts, {} to {}", hosts.len(), first.name, last.name)
+        }
+        _ => {
+            let names: Vec<&str> = hosts.iter().map(|h| h.name.as_str()).collect();
+            format!("hosts {}", names.join(", "))
+        }
+    }
+}
+
+/// Split a spec on commas, except those inside a `{a,b}` list
+fn split_spec(spec: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&spec[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&spec[start..]);
+    items
+}
+
+/// Most names a pattern may expand to, so a typo like `web[1-99999999]`
+/// is an error rather than using up all memory
+const MAX_EXPANSION: u64 = 10_000;
+
+/// Expand numeric ranges such as `web[01-20]` (zero padded to the width of
+/// the start) and lists such as `db{1,3,5}` into the names they describe.
+/// Brackets which don't hold a numeric range, as around IPv6 addresses, are
+/// left alone.
+pub fn expand_pattern(pattern: &str) -> Result<Vec<String>> {
+    let (start, end, choices) = match find_expansion(pattern)? {
+        Some(expansion) => expansion,
+        None => return Ok(vec![pattern.into()]),
+    };
+    let prefix = &pattern[..start];
+    let suffixes = expand_pattern(&pattern[end..])?;
+    if (choices.len() as u64).saturating_mul(suffixes.len() as u64) > MAX_EXPANSION {
+        return Err(too_many(pattern));
+    }
+    let mut names = vec![];
+    for choice in &choices {
+        for suffix in &suffixes {
+            names.push(format!("{}{}{}", prefix, choice, suffix));
+        }
+    }
+    Ok(names)
+}
+
+/// Find the first range or list in `pattern`, returning where it starts and
+/// ends, and what it expands to
+fn find_expansion(pattern: &str) -> Result<Option<(usize, usize, Vec<String>)>> {
+    for (start, c) in pattern.char_indices() {
+        let close = match c {
+            '{' => '}',
+            '[' => ']',
+            _ => continue,
+        };
+        let end = match pattern[start..].find(close) {
+            Some(len) => start + len + 1,
+            None => continue,
+        };
+        let inner = &pattern[start + 1..end - 1];
+        if c == '{' && inner.contains(',') {
+            return Ok(Some((
+                start,
+                end,
+                inner.split(',').map(String::from).collect(),
+            )));
+        }
+        let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        match inner.split_once('-') {
+            Some((from, to)) if c == '[' && digits(from) && digits(to) => {
+                let bad_range = || HostsError::InvalidHost(format!("bad range in '{}'", pattern));
+                let first: u64 = from.parse().map_err(|_| bad_range())?;
+                let last: u64 = to.parse().map_err(|_| bad_range())?;
+                if first > last {
+                    return Err(bad_range());
+                }
+                if last - first >= MAX_EXPANSION {
+                    return Err(too_many(pattern));
+                }
+                let width = if from.starts_with('0') { from.len() } else { 0 };
+                let choices = (first..=last)
+                    .map(|n| format!("{:0width$}", n, width = width))
+                    .collect();
+                return Ok(Some((start, end, choices)));
+            }
+            _ => continue,
+        }
+    }
+    Ok(None)
+}
+
+fn too_many(pattern: &str) -> HostsError {
+    HostsError::InvalidHost(format!(
+        "'{}' expands to more than {} hosts",
+        pattern, MAX_EXPANSION
+    ))
+}
+
+fn split_filters(filters: &str) -> Vec<String> {
+    filters.split('+').map(String::from).collect()
+}
+
+/// Hosts resolved from a spec, and anything odd noticed along the way
+#[derive(Debug, Default)]
+pub struct Resolved {
+    pub hosts: Vec<Host>,
+    pub warnings: Vec<String>,
+}
+
+/// Resolve a comma separated host spec into hosts, see `SpecItem` for what
+/// each item may be. Hosts are deduplicated by name, and invalid names are
+/// rejected. If `names` is not empty, only hosts with a name matching one of
+/// them are kept.
+pub fn resolve_hosts(spec: &str, names: &[Regex]) -> Result<Resolved> {
+    let mut resolved = resolve_with(spec, default_hosts_file().as_deref())?;
+    if !names.is_empty() {
+        resolved
+            .hosts
+            .retain(|host| names.iter().any(|re| re.is_match(&host.name)));
+    }
+    Ok(resolved)
+}
+
+/// Names of hosts which don't resolve in DNS. Hosts reached through a jump
+/// host are skipped, as they need only resolve for it.
+pub fn unresolvable(hosts: &[Host]) -> Vec<String> {
+    hosts
+        .iter()
+        .filter(|host| host.jump.is_none())
+        .filter(|host| {
+            let name = host.name.rsplit('@').next().unwrap_or_default();
+            match (name, host.port.unwrap_or(22)).to_socket_addrs() {
+                Ok(mut addrs) => addrs.next().is_none(),
+                Err(_) => true,
+            }
+        })
+        .map(|host| host.name.clone())
+        .collect()
+}
+
+/// Names a host's directory can't have in the output directory, as they
+/// lead out of it or are the run's own files
+const RESERVED_NAMES: [&str; 5] = [".", "..", "run.json", "timing.json", "askpass"];
+
+/// Reject names which can't be a host, which ssh would take as an option,
+/// or which can't name the host's directory in the output directory
+fn validate_name(name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "._-:@%".contains(c);
+    if name.is_empty()
+        || name.starts_with('-')
+        || !name.chars().all(valid)
+        || RESERVED_NAMES.contains(&name)
+    {
+        return Err(HostsError::InvalidHost(format!(
+            "'{}' is not a valid host name",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Compile a host name glob, where `*` matches anything and `?` matches one
+/// character, into a regex matching the whole name
+pub fn glob_regex(glob: &str) -> Result<Regex> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    name_regex(&pattern)
+}
+
+/// Compile a regex for matching host names
+pub fn name_regex(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).map_err(|err| HostsError::InvalidPattern(err.to_string()))
+}
+
+/// Resolve a spec, taking hosts for tag filters from `hosts_file`
+fn resolve_with(spec: &str, hosts_file: Option<&Path>) -> Result<Resolved> {
+    let mut resolved = Resolved::default();
+    let mut known: Option<Vec<Host>> = None;
+    // the port and spec item each host was first found with, by name
+    let mut seen: HashMap<String, (Option<u16>, String)> = HashMap::new();
+    for item in split_spec(spec)
+        .into_iter()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        let found = match item.parse()? {
+            SpecItem::Hosts(items) => items,
+            SpecItem::File(path) => read_hosts_file(&path)?,
+            SpecItem::Aws(query) => query.resolve()?,
+            SpecItem::Kubectl(selector) => kubectl::pods(&selector)?,
+            SpecItem::Containers(engine, filters) => engine.containers(&filters)?,
+            SpecItem::Tags(filter) => {
+                if known.is_none() {
+                    let path = hosts_file.ok_or(HostsError::NoHostsFile)?;
+                    known = Some(read_hosts_file(path)?);
+                }
+                let known = known.as_deref().unwrap_or_default();
+                known
+                    .iter()
+                    .filter(|h| matches_filter(h, &filter))
+                    .cloned()
+                    .collect()
+            }
+        };
+        for host in found {
+            validate_name(&host.name)?;
+            match seen.get(&host.name) {
+                None => {
+                    seen.insert(host.name.clone(), (host.port, item.into()));
+                    resolved.hosts.push(host);
+                }
+                Some((port, _)) if *port != host.port => {
+                    return Err(HostsError::InvalidHost(format!(
+                        "{} is listed with different ports",
+                        host.name
+                    )))
+                }
+                Some((_, first)) if first != item => resolved.warnings.push(format!(
+                    "{} is selected by both {} and {}",
+                    host.name, first, item
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// Where the hosts file lives if not specified, `$XDG_CONFIG_HOME/bdsh/hosts`
+/// falling back to `~/.config/bdsh/hosts`.
+pub fn default_hosts_file() -> Option<PathBuf> {
+    Some(config_dir()?.join("hosts"))
+}
+
+/// Tags a host must have, and must not have, to be selected. Filters in
+/// separate spec items are alternatives, so `:web:!canary,:db` is every
+/// non-canary web host plus every db host.
+#[derive(Debug, Default, PartialEq)]
+pub struct TagFilter {
+    pub require: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl TagFilter {
+    fn describe(&self) -> String {
+        let mut parts = vec![];
+        if !self.require.is_empty() {
+            parts.push(format!("tagged {}", self.require.join(" and ")));
+        }
+        if !self.exclude.is_empty() {
+            parts.push(format!("not tagged {}", self.exclude.join(" or ")));
+        }
+        parts.join(" but ")
+    }
+}
+
+/// Parse a tag filter such as `:web:prod:!canary`
+pub fn parse_tag_filter(filter: &str) -> Result<TagFilter> {
+    let mut parsed = TagFilter::default();
+    for tag in filter.split(':').skip(1) {
+        match tag.strip_prefix('!') {
+            Some(tag) => parsed.exclude.push(parse_tag(tag)?),
+            None => parsed.require.push(parse_tag(tag)?),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Does `host` have every required tag in `filter`, and none of the excluded?
+pub fn matches_filter(host: &Host, filter: &TagFilter) -> bool {
+    filter.require.iter().all(|tag| host.tags.contains(tag))
+        && !filter.exclude.iter().any(|tag| host.tags.contains(tag))
+}
+
+/// Parse tags such as `:web:prod` as written after a host in a hosts file
+fn parse_tags(tags: &str) -> Result<Vec<String>> {
+    tags.split(':').skip(1).map(parse_tag).collect()
+}
+
+/// Can `tag` be written in a hosts file and used in a filter?
+pub fn valid_tag(tag: &str) -> bool {
+    let valid = |c: char| !(c.is_whitespace() || "#:,@!".contains(c));
+    !tag.is_empty() && tag.chars().all(valid)
+}
+
+fn parse_tag(tag: &str) -> Result<String> {
+    if !valid_tag(tag) {
+        return Err(HostsError::InvalidTag(tag.into()));
+    }
+    Ok(tag.into())
+}
+
+/// Read hosts from a file, one per line, as `host [:tag ...] [jump=host]`.
+/// Blank lines and anything following a `#` are ignored.
+pub fn read_hosts_file(path: &Path) -> Result<Vec<Host>> {
+    read_hosts_file_from(path, &mut vec![])
+}
+
+/// Read a hosts file, following `include` lines. `including` holds the
+/// files that led here, to catch include loops.
+fn read_hosts_file_from(path: &Path, including: &mut Vec<PathBuf>) -> Result<Vec<Host>> {
+    let io_err = |err| HostsError::Io {
+        path: path.display().to_string(),
+        source: err,
+    };
+    let data = fs::read_to_string(path).map_err(io_err)?;
+    let canonical = path.canonicalize().map_err(io_err)?;
+    if including.contains(&canonical) {
+        return Err(HostsError::IncludeLoop(path.display().to_string()));
+    }
+    including.push(canonical);
+    let mut hosts = vec![];
+    for line in data.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        if let Some(pattern) = line.trim().strip_prefix("include ") {
+            for included in include_paths(path, pattern.trim()).map_err(io_err)? {
+                hosts.extend(read_hosts_file_from(&included, including)?);
+            }
+        } else if !line.trim().is_empty() {
+            hosts.extend(parse_hosts_line(line)?);
+        }
+    }
+    including.pop();
+    Ok(hosts)
+}
+
+/// Parse a hosts file line, which may name several hosts with a pattern
+fn parse_hosts_line(line: &str) -> Result<Vec<Host>> {
+    let mut tokens = line.split_whitespace();
+    let pattern = tokens.next().unwrap_or_default();
+    let mut host = Host::default();
+    for token in tokens {
+        if let Some(jump) = token.strip_prefix("jump=") {
+            host.jump = Some(jump.into());
+        } else if token.starts_with(':') {
+            host.tags.extend(parse_tags(token)?);
+        } else {
+            return Err(HostsError::InvalidHost(format!(
+                "expected a :tag or jump= but found '{}' in '{}'",
+                token,
+                line.trim()
+            )));
+        }
+    }
+    expand_pattern(pattern)?
+        .iter()
+        .map(|name| {
+            let parsed: Host = name.parse()?;
+            Ok(Host {
+                name: parsed.name,
+                port: parsed.port,
+                ..host.clone()
+            })
+        })
+        .collect()
+}
+
+/// Add `tag` to `host` in the contents of a hosts file, appending a line for
+/// the host if it isn't present. Everything else is left untouched. A host
+/// written as one of several in a pattern can't be tagged alone.
+pub fn add_tag(contents: &str, host: &str, tag: &str) -> Result<String> {
+    let tag = parse_tag(tag.trim_start_matches(':'))?;
+    let host = host.trim();
+    let name = host.parse::<Host>()?.name;
+    // a new line must read back as this host, not an include
+    validate_name(&name)?;
+    if name == "include" {
+        return Err(HostsError::InvalidHost(
+            "'include' can't be a host in a hosts file".into(),
+        ));
+    }
+    let mut found = false;
+    let mut lines = vec![];
+    for line in contents.lines() {
+        let (data, comment) = split_comment(line);
+        if !line_has_host(data, &name)? {
+            lines.push(line.to_string());
+            continue;
+        }
+        found = true;
+        if line_tags(data).any(|t| t == tag) {
+            lines.push(line.to_string());
+            continue;
+        }
+        let trimmed = data.trim_end();
+        let spacing = &data[trimmed.len()..];
+        lines.push(format!("{} :{}{}{}", trimmed, tag, spacing, comment));
+    }
+    if !found {
+        lines.push(format!("{} :{}", host, tag));
+    }
+    Ok(join_lines(lines, contents))
+}
+
+/// Remove `tag` from `host` in the contents of a hosts file. Everything else
+/// is left untouched.
+pub fn remove_tag(contents: &str, host: &str, tag: &str) -> Result<String> {
+    let tag = parse_tag(tag.trim_start_matches(':'))?;
+    let name = host.parse::<Host>()?.name;
+    let mut found = false;
+    let mut lines = vec![];
+    for line in contents.lines() {
+        let (data, comment) = split_comment(line);
+        if !line_has_host(data, &name)? {
+            lines.push(line.to_string());
+            continue;
+        }
+        found = true;
+        let mut tokens = vec![];
+        let mut rest = data;
+        // keep each token along with the whitespace in front of it
+        while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+            let end = rest[start..]
+                .find(char::is_whitespace)
+                .map_or(rest.len(), |i| start + i);
+            tokens.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+        let mut edited = String::new();
+        for token in tokens {
+            let trimmed = token.trim_start();
+            if !trimmed.starts_with(':') {
+                edited.push_str(token);
+                continue;
+            }
+            let tags: Vec<&str> = trimmed.split(':').skip(1).filter(|t| *t != tag).collect();
+            if !tags.is_empty() {
+                edited.push_str(&token[..token.len() - trimmed.len()]);
+                edited.push(':');
+                edited.push_str(&tags.join(":"));
+            }
+        }
+        lines.push(format!("{}{}{}", edited, rest, comment));
+    }
+    if !found {
+        return Err(HostsError::UnknownHost(host.into()));
+    }
+    Ok(join_lines(lines, contents))
+}
+
+/// Write a hosts file by way of a temp file, so a failure can't leave it
+/// half written.
+pub fn write_hosts_file(path: &Path, contents: &str) -> Result<()> {
+    let io_err = |err| HostsError::Io {
+        path: path.display().to_string(),
+        source: err,
+    };
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents).map_err(io_err)?;
+    fs::rename(&tmp, path).map_err(io_err)
+}
+
+fn split_comment(line: &str) -> (&str, &str) {
+    line.split_at(line.find('#').unwrap_or(line.len()))
+}
+
+/// Whether a hosts file line is for `name` alone. A line naming it among
+/// other hosts with a pattern can't be edited for it alone, so is an error.
+fn line_has_host(data: &str, name: &str) -> Result<bool> {
+    let Some(token) = data.split_whitespace().next() else {
+        return Ok(false);
+    };
+    if data.trim().starts_with("include ") {
+        return Ok(false);
+    }
+    let names: Vec<String> = expand_pattern(token)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|name| name.parse::<Host>().ok())
+        .map(|host| host.name)
+        .collect();
+    if !names.iter().any(|n| n == name) {
+        return Ok(false);
+    }
+    if names.len() > 1 {
+        return Err(HostsError::InPattern {
+            host: name.into(),
+            pattern: token.into(),
+        });
+    }
+    Ok(true)
+}
+
+fn line_tags(data: &str) -> impl Iterator<Item = &str> {
+    data.split_whitespace()
+        .skip(1)
+        .filter(|token| token.starts_with(':'))
+        .flat_map(|token| token.split(':').skip(1))
+}
+
+fn join_lines(lines: Vec<String>, original: &str) -> String {
+    let mut joined = lines.join("\n");
+    if original.is_empty() || original.ends_with('\n') {
+        joined.push('\n');
+    }
+    joined
+}
+
+/// One documented form of the host spec or hosts file grammar
+pub struct Syntax {
+    pub form: &'static str,
+    pub about: &'static str,
+    pub examples: &'static [&'static str],
+}
+
+/// Items accepted in a host spec, shown by `bdsh help filters`
+pub const SPEC_SYNTAX: &[Syntax] = &[
+    Syntax {
+        form: "host[:port]",
+        about: "a single host by name or address, optionally with an ssh port",
+        examples: &["freki", "freki:2222"],
+    },
+    Syntax {
+        form: "[address]:port",
+        about: "an IPv6 address with a port; bare IPv6 addresses can't have one",
+        examples: &["[fe80::1]:2222", "fe80::1"],
+    },
+    Syntax {
+        form: "name[N-M]  name{a,b,...}",
+        about: "several hosts from a numeric range, zero padded like N, or a list",
+        examples: &["web[01-20].example.com", "db{1,3,5}:2222"],
+    },
+    Syntax {
+        form: "@path",
+        about: "the hosts listed in a file, see `bdsh help hosts-format`",
+        examples: &["@hosts.txt"],
+    },
+    Syntax {
+        form: ":tag[:tag...][:!tag...]",
+        about: "hosts in the default hosts file which have every tag given, and none \
+                of those marked with !",
+        examples: &[":web", ":db:prod", ":prod:!maintenance"],
+    },
+    Syntax {
+        form: "@aws:name=value[+name=value...]",
+        about: "running EC2 instances matching describe-instances filters, via the aws cli; \
+                instance tags become `Key=Value` tags, dns=public uses public DNS names, \
+                and dns=id instance ids (for --backend ssm)",
+        examples: &[
+            "@aws:tag:Role=web",
+            "@aws:tag:Role=db+dns=public",
+            "@aws:tag:Role=batch+dns=id",
+        ],
+    },
+    Syntax {
+        form: "@kubectl:selector[+selector...]",
+        about: "running pods in kubectl's current namespace matching label selectors, \
+                for use with --backend kubectl",
+        examples: &["@kubectl:app=web", "@kubectl:app=web+tier!=canary"],
+    },
+    Syntax {
+        form: "@docker:filter[+filter...]",
+        about: "running local containers matching `docker ps` filters, for use with \
+                --backend docker; @podman: does the same with podman",
+        examples: &["@docker:label=app=foo", "@podman:label=app=foo+name=web"],
+    },
+];
+
+/// Lines accepted in a hosts file, shown by `bdsh help hosts-format`
+pub const HOSTS_FILE_SYNTAX: &[Syntax] = &[
+    Syntax {
+        form: "host[:port]",
+        about: "a host, in any form a single host takes in a host spec",
+        examples: &["freki", "[fe80::1]:2222"],
+    },
+    Syntax {
+        form: "name[N-M] ...  name{a,b,...} ...",
+        about: "several hosts sharing the rest of the line, as in a host spec",
+        examples: &["web[01-03] :web"],
+    },
+    Syntax {
+        form: "host :tag[:tag...] ...",
+        about: "a host with tags, for selecting it with `:tag` in a host spec",
+        examples: &["db1 :db:prod :primary"],
+    },
+    Syntax {
+        form: "host jump=[user@]host",
+        about: "a host reached through a jump host, overriding --jump",
+        examples: &["db2:2222 jump=ops@bastion :db"],
+    },
+];
+
+/// Help for host specs, with examples run through the parser
+pub fn spec_help() -> String {
+    let mut help = String::from(
+        "A host spec is a comma separated list of items, and selects every host\n\
+         that any of them select. Each item is one of:\n",
+    );
+    for syntax in SPEC_SYNTAX {
+        help.push_str(&render_syntax(syntax, |example| {
+            example.parse::<SpecItem>().map(|item| item.describe())
+        }));
+    }
+    help
+}
+
+/// Help for the hosts file format, with examples run through the parser
+pub fn hosts_file_help() -> String {
+    let mut help = String::from(
+        "A hosts file lists one host per line. Blank lines and anything after\n\
+         a `#` are ignored. `include PATH` reads the hosts in another file,\n\
+         relative to this one, and may use * and ? in the file name to read\n\
+         several. Each other line is one of:\n",
+    );
+    for syntax in HOSTS_FILE_SYNTAX {
+        help.push_str(&render_syntax(syntax, |example| {
+            parse_hosts_line(example).map(|hosts| describe_hosts(&hosts))
+        }));
+    }
+    help
+}
+
+fn render_syntax(syntax: &Syntax, describe: impl Fn(&str) -> Result<String>) -> String {
+    let mut out = format!("\n  {}\n      {}\n", syntax.form, syntax.about);
+    for example in syntax.examples {
+        let described = describe(example).unwrap_or_else(|err| err.to_string());
+        out.push_str(&format!("      {:<32} {}\n", example, described));
+    }
+    out
+}
+
+#[derive(Error, Debug)]
+pub enum HostsError {
+    #[error("unable to read hosts file {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("invalid host: {0}")]
+    InvalidHost(String),
+
+    #[error("invalid host name pattern: {0}")]
+    InvalidPattern(String),
+
+    #[error("invalid tag: '{0}'")]
+    InvalidTag(String),
+
+    #[error("unable to get hosts from {source_name}: {msg}")]
+    Source { source_name: String, msg: String },
+
+    #[error("no such host in hosts file: {0}")]
+    UnknownHost(String),
+
+    #[error("{host} is written as one of the hosts of '{pattern}', give it a line of its own")]
+    InPattern { host: String, pattern: String },
+
+    #[error("tag filters need a hosts file, but no home directory was found")]
+    NoHostsFile,
+
+    #[error("{0} includes itself")]
+    IncludeLoop(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn host(name: &str, port: Option<u16>) -> Host {
+        Host {
+            name: name.into(),
+            port,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_host_parse() {
+        assert_eq!("freki".parse::<Host>().unwrap(), host("freki", None));
+        assert_eq!(
+            "freki:2222".parse::<Host>().unwrap(),
+            host("freki", Some(2222))
+        );
+        assert_eq!("::1".parse::<Host>().unwrap(), host("::1", None));
+        assert_eq!("[::1]".parse::<Host>().unwrap(), host("::1", None));
+        assert_eq!(
+            "[fe80::1]:2222".parse::<Host>().unwrap(),
+            host("fe80::1", Some(2222))
+        );
+        assert!("freki:ssh".parse::<Host>().is_err());
+        assert!("[::1".parse::<Host>().is_err());
+        assert!("[::1]2222".parse::<Host>().is_err());
+    }
+
+    #[test]
+    fn test_dedupe_and_validate() {
+        let resolved = resolve_hosts("db{1,1,2},db[1-3]", &[]).unwrap();
+        let names: Vec<&str> = resolved.hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, vec!["db1", "db2", "db3"]);
+        assert_eq!(resolved.warnings.len(), 2);
+
+        assert!(resolve_hosts("db1,db1:2222", &[]).is_err());
+        assert!(resolve_hosts("-oProxyCommand=sh", &[]).is_err());
+        assert!(resolve_hosts("web;reboot", &[]).is_err());
+        assert!(resolve_hosts("..", &[]).is_err());
+        assert!(resolve_hosts("web1,.", &[]).is_err());
+        assert!(resolve_hosts("run.json", &[]).is_err());
+        assert!(resolve_hosts("brian@freki,fe80::1%eth0", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_expand_pattern() {
+        assert_eq!(expand_pattern("freki").unwrap(), vec!["freki"]);
+        assert_eq!(
+            expand_pattern("web[08-10].prod").unwrap(),
+            vec!["web08.prod", "web09.prod", "web10.prod"]
+        );
+        assert_eq!(expand_pattern("db[9-10]").unwrap(), vec!["db9", "db10"]);
+        assert_eq!(
+            expand_pattern("{a,b}[1-2]").unwrap(),
+            vec!["a1", "a2", "b1", "b2"]
+        );
+        assert_eq!(
+            expand_pattern("[fe80::1]:22").unwrap(),
+            vec!["[fe80::1]:22"]
+        );
+        assert!(expand_pattern("web[3-1]").is_err());
+        assert!(expand_pattern("h[1-99999999]").is_err());
+        assert!(expand_pattern("h[1-200][1-200]").is_err());
+        assert_eq!(expand_pattern("h[1-100][1-100]").unwrap().len(), 10_000);
+
+        let hosts = parse_hosts_line("web[1-2]:2222 :web").unwrap();
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[1].name, "web2");
+        assert_eq!(hosts[1].port, Some(2222));
+        assert_eq!(hosts[1].tags, vec!["web"]);
+    }
+
+    #[test]
+    fn test_resolve_hosts() {
+        let hosts = resolve_hosts("freki,geri:2222,", &[]).unwrap().hosts;
+        assert_eq!(hosts, vec![host("freki", None), host("geri", Some(2222))]);
+
+        let hosts = resolve_hosts("db{1,3},web1", &[]).unwrap().hosts;
+        assert_eq!(
+            hosts,
+            vec![host("db1", None), host("db3", None), host("web1", None)]
+        );
+
+        let names = [
+            glob_regex("web-*.eu").unwrap(),
+            name_regex("^db[0-9]$").unwrap(),
+        ];
+        let hosts = resolve_hosts("web-1.eu,web-1.us,xweb-2.eu,db1,db10", &names)
+            .unwrap()
+            .hosts;
+        let hosts: Vec<&str> = hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(hosts, vec!["web-1.eu", "db1"]);
+    }
+
+    #[test]
+    fn test_parse_hosts_line() {
+        let host = parse_hosts_line("db1:2222  :db:prod :primary")
+            .unwrap()
+            .remove(0);
+        assert_eq!(host.name, "db1");
+        assert_eq!(host.port, Some(2222));
+        assert_eq!(host.tags, vec!["db", "prod", "primary"]);
+        assert!(matches_filter(
+            &host,
+            &parse_tag_filter(":prod:db").unwrap()
+        ));
+        assert!(!matches_filter(&host, &parse_tag_filter(":web").unwrap()));
+        assert!(!matches_filter(
+            &host,
+            &parse_tag_filter(":db:!primary").unwrap()
+        ));
+        assert!(matches_filter(&host, &parse_tag_filter(":!web").unwrap()));
+        assert!(parse_tag_filter(":db:!").is_err());
+        assert!(parse_tags(":!db").is_err());
+        assert!(parse_hosts_line("db1 prod").is_err());
+
+        let host = parse_hosts_line("db2 jump=ops@bastion :db")
+            .unwrap()
+            .remove(0);
+        assert_eq!(host.jump.as_deref(), Some("ops@bastion"));
+        assert_eq!(host.ssh_args(), vec!["-J", "ops@bastion", "db2"]);
+    }
+
+    #[test]
+    fn test_edit_tags() {
+        let contents = "# databases\ndb1  :db   # primary\nweb1:2222 :web:prod\n";
+        assert_eq!(
+            add_tag(contents, "db1", ":prod").unwrap(),
+            "# databases\ndb1  :db :prod   # primary\nweb1:2222 :web:prod\n"
+        );
+        assert_eq!(add_tag(contents, "db1", "db").unwrap(), contents);
+        assert_eq!(
+            add_tag(contents, "cache1", "cache").unwrap(),
+            format!("{}cache1 :cache\n", contents)
+        );
+        assert_eq!(
+            remove_tag(contents, "web1", ":web").unwrap(),
+            "# databases\ndb1  :db   # primary\nweb1:2222 :prod\n"
+        );
+        assert_eq!(
+            remove_tag(contents, "db1", "db").unwrap(),
+            "# databases\ndb1   # primary\nweb1:2222 :web:prod\n"
+        );
+        assert_eq!(
+            add_tag(contents, "web1:2222", "canary").unwrap(),
+            "# databases\ndb1  :db   # primary\nweb1:2222 :web:prod :canary\n"
+        );
+        for bad in ["web 1", "web#1", "web1:x", "-web1"] {
+            assert!(add_tag(contents, bad, "canary").is_err(), "{}", bad);
+        }
+        assert!(remove_tag(contents, "cache1", "db").is_err());
+
+        let contents = "include hosts.d/*\nweb[01-03] :web\n";
+        assert!(matches!(
+            add_tag(contents, "web02", "canary"),
+            Err(HostsError::InPattern { .. })
+        ));
+        assert!(remove_tag(contents, "web02", "web").is_err());
+        assert!(add_tag(contents, "include", "x").is_err());
+        assert!(remove_tag(contents, "include", "x").is_err());
+        assert_eq!(
+            add_tag("web[01-01]\n", "web01", "x").unwrap(),
+            "web[01-01] :x\n"
+        );
+    }
+
+    #[test]
+    fn test_tag_filter_groups() {
+        let dir = std::env::temp_dir().join(format!("bdsh-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("hosts.d")).unwrap();
+        let path = dir.join("hosts");
+        fs::write(&path, "web1 :web:prod\ninclude hosts.d/*.hosts\n").unwrap();
+        fs::write(dir.join("hosts.d/canary.hosts"), "web2 :web:prod:canary\n").unwrap();
+        fs::write(dir.join("hosts.d/db.hosts"), "include canary.tmp\n").unwrap();
+        fs::write(dir.join("hosts.d/canary.tmp"), "db1 :db:prod:canary\n").unwrap();
+        let select = |spec: &str| -> Vec<String> {
+            let resolved = resolve_with(spec, Some(&path)).unwrap();
+            resolved.hosts.into_iter().map(|h| h.name).collect()
+        };
+
+        // negation binds within a group, groups are alternatives
+        assert_eq!(select(":prod:!canary"), vec!["web1"]);
+        assert_eq!(select(":web:!canary,:db"), vec!["web1", "db1"]);
+        assert_eq!(select(":!canary,:canary:!db"), vec!["web1", "web2"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_syntax_examples_parse() {
+        for syntax in SPEC_SYNTAX {
+            for example in syntax.examples {
+                assert!(example.parse::<SpecItem>().is_ok(), "{}", example);
+            }
+        }
+        for syntax in HOSTS_FILE_SYNTAX {
+            for example in syntax.examples {
+                assert!(parse_hosts_line(example).is_ok(), "{}", example);
+            }
+        }
+    }
+}
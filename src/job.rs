@@ -0,0 +1,529 @@
+use crate::containers::Engine;
+use crate::hosts::Host;
+use crate::secret::SECRET_VAR;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Files the script writes in the job directory, see `Job::write_script`
+pub const STARTED: &str = "started";
+pub const FINISHED: &str = "finished";
+pub const STATUS: &str = "status";
+
+/// Where a job's output goes, and where it ends up if compressed
+pub const LOG: &str = "out.log";
+pub const COMPRESSED_LOG: &str = "out.log.gz";
+
+#[derive(Debug)]
+pub struct Job {
+    /// Directory this job executes in
+    pub root: PathBuf,
+
+    /// host to run command on
+    pub host: Host,
+
+    /// how to reach the host
+    pub transport: Transport,
+
+    /// command to run
+    pub command: String,
+}
+
+/// How to reach hosts, shared by all the jobs in a run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transport {
+    pub backend: Backend,
+
+    /// ssh, or a replacement for it, as sh text so it may carry arguments
+    pub ssh_command: String,
+
+    /// options passed to ssh ahead of the host
+    pub ssh_options: Vec<String>,
+
+    /// environment for the script, kept out of it and out of run.json as
+    /// it may hold a secret, see `secret::askpass_env`
+    #[serde(skip)]
+    pub env: Vec<(String, String)>,
+
+    /// pipe the run's secret to the command's stdin, for `sudo -S`
+    pub secret_stdin: bool,
+
+    /// ssh settings for hosts with a tag, by tag, from `[tag.NAME]` in
+    /// config
+    pub tags: BTreeMap<String, TagSsh>,
+}
+
+/// ssh settings for the hosts with a tag, over the run's own. A host with
+/// several such tags takes the first ssh-cmd, in the order of its tags,
+/// and every ssh-args.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TagSsh {
+    pub ssh_cmd: Option<String>,
+    /// passed to ssh ahead of the run's options, so they win
+    pub ssh_args: Vec<String>,
+}
+
+impl Transport {
+    /// The run's secret, if it has one, see `secret::Secret`
+    pub fn secret(&self) -> Option<&str> {
+        self.env
+            .iter()
+            .find(|(key, _)| key == SECRET_VAR)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport {
+            backend: Backend::default(),
+            ssh_command: "ssh".into(),
+            ssh_options: vec![],
+            env: vec![],
+            secret_stdin: false,
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+impl Job {
+    pub fn new(output_dir: &Path, host: Host, transport: &Transport, command: &str) -> Job {
+        Job {
+            root: output_dir.join(&host.name),
+            host,
+            transport: transport.clone(),
+            command: command.into(),
+        }
+    }
+
+    fn script_path(&self) -> PathBuf {
+        self.root.join("command")
+    }
+
+    fn log_path(&self) -> PathBuf {
+        self.root.join(LOG)
+    }
+
+    /// Write out the `command` script for this job, which is the command
+    /// prefixed by ssh (or the backend's equivalent) and its arguments.
+    /// Around it, the script records when it started and finished, as unix
+    /// seconds in `started` and `finished`, and the exit status in `status`,
+    /// which is written last. Running `sh command` from the job directory
+    /// replicates the remote run.
+    pub fn write_script(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.root)?;
+        let (program, args) = self.transport.backend.argv(self);
+        let mut line = vec![];
+        if self.transport.secret_stdin {
+            line.push(format!(r#"printf '%s\n' "${}" |"#, SECRET_VAR));
+        }
+        line.push(program);
+        line.extend(args.iter().map(|arg| shell_quote(arg)));
+        let file = |name: &str| shell_quote(&self.root.join(name).to_string_lossy());
+        let script = [
+            "#!/bin/sh".to_string(),
+            format!("date +%s > {}", file(STARTED)),
+            line.join(" "),
+            "status=$?".into(),
+            format!("date +%s > {}", file(FINISHED)),
+            format!("echo $status > {}", file(STATUS)),
+            "exit $status".into(),
+        ];
+        fs::write(self.script_path(), script.join("\n") + "\n")
+    }
+
+    /// Command to run the script directly, without tmux. Its output, with
+    /// stderr folded in, is piped, and it runs in its own process group so
+    /// it can be killed along with everything it starts.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", r#"exec sh "$0" 2>&1"#])
+            .arg(self.script_path())
+            .envs(self.transport.env.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .process_group(0);
+        command
+    }
+
+    /// Command for the tmux window: run the script, capturing output with
+    /// tee, or with `capture` when output is capped or scrubbed, then gzip the log once
+    /// output ends if `compress`, and ring the bell if it failed and `bell`
+    pub fn window_command(&self, capture: &Capture, compress: bool, bell: bool) -> String {
+        let log = shell_quote(&self.log_path().to_string_lossy());
+        let sink = match capture {
+            Capture::Tee => format!("tee {}", log),
+            Capture::Bdsh {
+                exe,
+                max_bytes,
+                scrub,
+            } => {
+                let mut sink = format!("{} capture", shell_quote(exe));
+                if let Some(max_bytes) = max_bytes {
+                    sink.push_str(&format!(" --max-bytes {}", max_bytes));
+                }
+                if *scrub {
+                    sink.push_str(" --scrub");
+                }
+                format!("{} {}", sink, log)
+            }
+        };
+        let mut command = format!(
+            "sh {} | {}",
+            shell_quote(&self.script_path().to_string_lossy()),
+            sink
+        );
+        if compress {
+            command.push_str(&format!("; gzip -f {}", log));
+        }
+        if bell {
+            // linger a moment, or tmux closes the window before it notices
+            let status = shell_quote(&self.root.join(STATUS).to_string_lossy());
+            command.push_str(&format!(
+                r#"; [ "$(cat {})" = 0 ] || {{ printf '\a'; sleep 1; }}"#,
+                status
+            ));
+        }
+        command
+    }
+}
+
+/// How a job's output gets into its out.log
+#[derive(Debug)]
+pub enum Capture {
+    /// all of it, with tee
+    Tee,
+    /// with `bdsh capture`, keeping only the start and end of it if
+    /// `max_bytes`, and hiding the run's secret if `scrub`
+    Bdsh {
+        exe: String,
+        max_bytes: Option<u64>,
+        scrub: bool,
+    },
+}
+
+/// How commands get to hosts
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// ssh to each host
+    #[default]
+    Ssh,
+    /// `kubectl exec` into each host, which is a pod
+    Kubectl,
+    /// `docker exec` into each host, which is a container
+    Docker,
+    /// `podman exec` into each host, which is a container
+    Podman,
+    /// Run the command here, once per host, with BDSH_HOST set to the host;
+    /// for commands which reach the host themselves, e.g. rsync
+    Local,
+    /// AWS SSM Session Manager, for EC2 instances without ssh access; hosts
+    /// are instance ids, e.g. from `@aws:...+dns=id`
+    Ssm,
+}
+
+impl Backend {
+    /// Whether a job exiting with `status` never reached its host, rather
+    /// than its command failing there: ssh exits 255 for its own errors
+    pub fn unreachable(&self, status: Option<i32>) -> bool {
+        *self == Backend::Ssh && status == Some(255)
+    }
+
+    /// The command line which runs `job`'s command on its host, as the
+    /// program (sh text, used as is) and its arguments (to be quoted)
+    fn argv(&self, job: &Job) -> (String, Vec<String>) {
+        let mut args = vec![];
+        let program = match self {
+            Backend::Ssh => {
+                let tagged: Vec<&TagSsh> = job
+                    .host
+                    .tags
+                    .iter()
+                    .filter_map(|tag| job.transport.tags.get(tag))
+                    .collect();
+                args.extend(tagged.iter().flat_map(|tag| tag.ssh_args.iter().cloned()));
+                args.extend(job.transport.ssh_options.iter().cloned());
+                args.extend(job.host.ssh_args());
+                tagged
+                    .iter()
+                    .find_map(|tag| tag.ssh_cmd.clone())
+                    .unwrap_or_else(|| job.transport.ssh_command.clone())
+            }
+            Backend::Kubectl => {
+                args.extend(["exec", "-i"].map(String::from));
+                args.push(job.host.name.clone());
+                args.extend(["--", "sh", "-c"].map(String::from));
+                "kubectl".into()
+            }
+            Backend::Docker | Backend::Podman => {
+                let engine = match self {
+                    Backend::Docker => Engine::Docker,
+                    _ => Engine::Podman,
+                };
+                args.extend(["exec", "-i"].map(String::from));
+                args.push(job.host.name.clone());
+                args.extend(["sh", "-c"].map(String::from));
+                engine.cli().into()
+            }
+            Backend::Local => {
+                args.push(format!("BDSH_HOST={}", job.host.name));
+                args.extend(["sh", "-c"].map(String::from));
+                "env".into()
+            }
+            Backend::Ssm => {
+                args.extend(["ssm", "start-session", "--target"].map(String::from));
+                args.push(job.host.name.clone());
+                args.extend(
+                    [
+                        "--document-name",
+                        "AWS-StartInteractiveCommand",
+                        "--parameters",
+                    ]
+                    .map(String::from),
+                );
+                args.push(ssm_parameters(&job.command));
+                return ("aws".into(), args);
+            }
+        };
+        args.push(job.command.clone());
+        (program, args)
+    }
+}
+
+/// The `--parameters` json for an SSM interactive command document
+fn ssm_parameters(command: &str) -> String {
+    serde_json::json!({ "command": [command] }).to_string()
+}
+
+/// How ssh should treat host keys it doesn't know or that have changed
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyPolicy {
+    /// Use whatever ssh is configured to do
+    #[default]
+    Default,
+    /// Refuse hosts whose key isn't already known
+    Strict,
+    /// Accept and remember keys for new hosts, refuse changed keys
+    AcceptNew,
+    /// Accept any key, and don't remember it
+    Ignore,
+}
+
+impl HostKeyPolicy {
+    /// Options to pass to ssh to apply this policy
+    pub fn ssh_options(&self) -> Vec<String> {
+        let opts: &[&str] = match self {
+            HostKeyPolicy::Default => &[],
+            HostKeyPolicy::Strict => &["-o", "StrictHostKeyChecking=yes"],
+            HostKeyPolicy::AcceptNew => &["-o", "StrictHostKeyChecking=accept-new"],
+            HostKeyPolicy::Ignore => &[
+                "-o",
+                "StrictHostKeyChecking=no",
+                "-o",
+                "UserKnownHostsFile=/dev/null",
+            ],
+        };
+        opts.iter().map(|opt| opt.to_string()).collect()
+    }
+}
+
+/// Prefix `command` with exports of `vars`, so they are set for everything
+/// the command runs.
+pub fn export_env(vars: &[(String, String)], command: &str) -> String {
+    let mut exports: Vec<String> = vars
+        .iter()
+        .map(|(key, value)| format!("export {}={};", key, shell_quote(value)))
+        .collect();
+    exports.push(command.into());
+    exports.join(" ")
+}
+
+/// Parse `KEY=VALUE`, or `KEY` to take the value from our own environment
+pub fn parse_env_var(var: &str) -> Result<(String, String), String> {
+    let (key, value) = match var.split_once('=') {
+        Some((key, value)) => (key, value.to_string()),
+        None => (
+            var,
+            std::env::var(var).map_err(|_| format!("{} is not set", var))?,
+        ),
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    if key.is_empty() || key.starts_with(|c: char| c.is_ascii_digit()) || !key.chars().all(valid) {
+        return Err(format!("invalid variable name '{}'", key));
+    }
+    Ok((key.into(), value))
+}
+
+/// Parse the variables in an env file, one `KEY[=VALUE]` per line, with
+/// blank lines and `#` comments ignored
+pub fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_env_var)
+        .collect()
+}
+
+/// Fill in a `--window-name` template for the `n`th host of a run, which
+/// has `status` so far
+pub fn window_name(template: &str, host: &str, n: usize, status: &str) -> String {
+    template
+        .replace("{host}", host)
+        .replace("{n}", &n.to_string())
+        .replace("{status}", status)
+}
+
+/// Wrap `command` to run as `user` via sudo. The command is handed to a
+/// shell so that pipes and the like run as `user` too. With `stdin`, sudo
+/// reads the password from stdin without prompting.
+pub fn sudo_command(user: &str, command: &str, stdin: bool) -> String {
+    format!(
+        "sudo {}-u {} -- sh -c {}",
+        if stdin { "-S -p '' " } else { "" },
+        shell_quote(user),
+        shell_quote(command)
+    )
+}
+
+/// Quote `arg` for sh, leaving it bare if it is obviously safe
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./:@=,+%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.into()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("freki"), "freki");
+        assert_eq!(shell_quote("uname -a"), "'uname -a'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_backend_argv() {
+        let host: Host = "web-6d4cf56db6-x2x7q".parse().unwrap();
+        let transport = Transport {
+            backend: Backend::Kubectl,
+            ..Default::default()
+        };
+        let job = Job::new(Path::new("/tmp"), host, &transport, "uname -a");
+        let (program, args) = Backend::Kubectl.argv(&job);
+        assert_eq!(program, "kubectl");
+        assert_eq!(
+            args,
+            vec![
+                "exec",
+                "-i",
+                "web-6d4cf56db6-x2x7q",
+                "--",
+                "sh",
+                "-c",
+                "uname -a"
+            ]
+        );
+
+        let host: Host = "freki:2222".parse().unwrap();
+        let transport = Transport {
+            ssh_command: "tsh ssh".into(),
+            ssh_options: vec!["-A".into()],
+            ..Default::default()
+        };
+        let job = Job::new(Path::new("/tmp"), host, &transport, "uname -a");
+        let (program, args) = Backend::Ssh.argv(&job);
+        assert_eq!(program, "tsh ssh");
+        assert_eq!(args, vec!["-A", "-p", "2222", "freki", "uname -a"]);
+
+        let mut host: Host = "freki".parse().unwrap();
+        host.tags = vec!["web".into(), "teleport".into()];
+        let mut transport = transport.clone();
+        transport.tags.insert(
+            "teleport".into(),
+            TagSsh {
+                ssh_cmd: Some("tsh ssh --proxy=tp".into()),
+                ssh_args: vec!["-o".into(), "User=ops".into()],
+            },
+        );
+        let job = Job::new(Path::new("/tmp"), host, &transport, "uname -a");
+        let (program, args) = Backend::Ssh.argv(&job);
+        assert_eq!(program, "tsh ssh --proxy=tp");
+        assert_eq!(args, vec!["-o", "User=ops", "-A", "freki", "uname -a"]);
+
+        let host: Host = "i-0abc".parse().unwrap();
+        let job = Job::new(Path::new("/tmp"), host, &transport, "uname -a");
+        let (program, args) = Backend::Ssm.argv(&job);
+        assert_eq!(program, "aws");
+        assert_eq!(args[3], "i-0abc");
+        assert_eq!(args.last().unwrap(), r#"{"command":["uname -a"]}"#);
+    }
+
+    #[test]
+    fn test_export_env() {
+        let vars = vec![
+            ("LC_ALL".to_string(), "C".to_string()),
+            ("GREETING".to_string(), "hello there".to_string()),
+        ];
+        assert_eq!(
+            export_env(&vars, "locale | head -1"),
+            "export LC_ALL=C; export GREETING='hello there'; locale | head -1"
+        );
+    }
+
+    #[test]
+    fn test_parse_env() {
+        assert_eq!(
+            parse_env_var("GREETING=hello there"),
+            Ok(("GREETING".into(), "hello there".into()))
+        );
+        assert!(parse_env_var("1X=y").is_err());
+        assert!(parse_env_var("A-B=c").is_err());
+        assert!(parse_env_var("BDSH_SURELY_UNSET_VAR").is_err());
+
+        let vars = parse_env_file("# deploy settings\n\nSTAGE=prod\nURL=https://x/?a=b\n").unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("STAGE".to_string(), "prod".to_string()),
+                ("URL".to_string(), "https://x/?a=b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_window_name() {
+        assert_eq!(
+            window_name("{n}:{host} [{status}]", "web1", 3, "exit 2"),
+            "3:web1 [exit 2]"
+        );
+        assert_eq!(window_name("db", "web1", 1, "ok"), "db");
+    }
+
+    #[test]
+    fn test_sudo_command() {
+        assert_eq!(
+            sudo_command("postgres", "psql -c 'select 1'", false),
+            r"sudo -u postgres -- sh -c 'psql -c '\''select 1'\'''"
+        );
+        assert_eq!(
+            sudo_command("root", "id", true),
+            "sudo -S -p '' -u root -- sh -c id"
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use crate::hosts::{Host, HostsError};
+use std::process::Command;
+
+type Result<T> = std::result::Result<T, HostsError>;
+
+/// Running pods matching a label selector, in kubectl's current namespace,
+/// for use with `--backend kubectl`
+pub fn pods(selector: &str) -> Result<Vec<Host>> {
+    let output = Command::new("kubectl")
+        .args(["get", "pods", "-o", "name", "-l", selector])
+        .arg("--field-selector=status.phase=Running")
+        .output()
+        .map_err(|err| HostsError::Source {
+            source_name: "kubectl".into(),
+            msg: format!("unable to run kubectl: {}", err),
+        })?;
+    if !output.status.success() {
+        return Err(HostsError::Source {
+            source_name: "kubectl".into(),
+            msg: String::from_utf8_lossy(&output.stderr).trim().into(),
+        });
+    }
+    Ok(hosts_from_names(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Turn `kubectl get -o name` output, `pod/<name>` per line, into hosts
+fn hosts_from_names(names: &str) -> Vec<Host> {
+    names
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| Host {
+            name: line.trim_start_matches("pod/").into(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hosts_from_names() {
+        let hosts = hosts_from_names("pod/web-1\npod/web-2\n\n");
+        let names: Vec<&str> = hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, vec!["web-1", "web-2"]);
+    }
+}
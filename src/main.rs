@@ -1,60 +1,1610 @@
 use anyhow::Result;
+use anyhow::{anyhow, bail, Context};
+use clap::{CommandFactory, Parser, Subcommand};
 use names::Generator;
+use std::collections::{BTreeSet, HashSet};
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
+use std::thread;
+use std::time::{Duration, Instant};
 
+mod answer;
+mod aws;
+mod capture;
+mod config;
+mod containers;
+mod demo;
+mod diff;
+mod direct;
+mod guard;
+mod history;
+mod hosts;
+mod job;
+mod kubectl;
+mod notify;
+mod report;
+mod run;
+mod schedule;
+mod secret;
 mod tmux;
 
-fn main() -> Result<()> {
-    // TODO add clap to take various arguments
+use hosts::Host;
+use job::{
+    export_env, parse_env_file, parse_env_var, shell_quote, sudo_command, window_name, Backend,
+    Capture, HostKeyPolicy, Job, Transport, STATUS,
+};
+
+/// Run a command on many hosts at once, in tmux
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    disable_help_subcommand = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    mode: Option<Mode>,
+
+    /// Hosts to run on, comma separated. Each is `host`, `host:port`,
+    /// `[v6addr]:port`, `@path` to read hosts from a file, or `:tag` to select
+    /// hosts with that tag (`:a:b` for both) from the hosts file.
+    #[arg(required_unless_present = "watch")]
+    hosts: Option<String>,
+
+    /// Command to run on each host
+    #[arg(required_unless_present = "watch", trailing_var_arg = true)]
+    command: Vec<String>,
+
+    /// Only run on hosts whose name matches this glob (`*` and `?`). May be
+    /// repeated, as may --match-re; a host matching any of them is kept.
+    #[arg(long = "match", value_name = "GLOB")]
+    match_glob: Vec<String>,
+
+    /// Only run on hosts whose name matches this regex
+    #[arg(long, value_name = "REGEX")]
+    match_re: Vec<String>,
+
+    /// Keep at most this many bytes of each host's output in its out.log,
+    /// half from the start and half from the end. The host's window still
+    /// shows everything.
+    #[arg(long, value_name = "BYTES")]
+    max_output_bytes: Option<u64>,
+
+    /// Gzip each host's out.log once its output ends
+    #[arg(long, overrides_with = "no_compress")]
+    compress: bool,
+
+    /// Don't gzip out.log, even if config says to
+    #[arg(long)]
+    no_compress: bool,
+
+    /// Don't ask before running: skip confirming guards and typing the host
+    /// count for big runs (see `confirm-over` in config)
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Ring the terminal bell and flash tmux's status line when a host
+    /// fails
+    #[arg(long, overrides_with = "no_bell")]
+    bell: bool,
+
+    /// Don't ring the bell, even if config says to
+    #[arg(long)]
+    no_bell: bool,
+
+    /// Run commands directly rather than in tmux, and report how each host
+    /// did. Hosts get no terminal, so can't be answered interactively.
+    #[arg(long)]
+    no_tmux: bool,
+
+    /// Run without tmux or any interaction, printing each host's output as
+    /// it finishes, for cron and CI
+    #[arg(long, conflicts_with = "no_tmux")]
+    batch: bool,
+
+    /// Run without tmux, printing every line of output as it arrives,
+    /// prefixed by its host, as dsh does
+    #[arg(long, conflicts_with_all = ["no_tmux", "batch"])]
+    stream: bool,
+
+    /// Run without tmux, and once every host finishes print each distinct
+    /// output once, with the hosts that produced it, as pssh -i does
+    #[arg(long, conflicts_with_all = ["no_tmux", "batch", "stream"])]
+    group: bool,
+
+    /// Run without tmux, printing a line per host as it finishes in a
+    /// format for scripts which won't change (see `bdsh help porcelain`)
+    #[arg(long, conflicts_with_all = ["no_tmux", "batch", "stream", "group"])]
+    porcelain: bool,
+
+    /// Write Prometheus metrics (hosts_total, hosts_failed,
+    /// run_duration_seconds) to FILE when the run ends, e.g. for
+    /// node_exporter's textfile collector
+    #[arg(long, value_name = "FILE")]
+    metrics_file: Option<PathBuf>,
+
+    /// POST a JSON summary of the run (command, and each host's status and
+    /// duration) to URL when it ends
+    #[arg(long, value_name = "URL")]
+    notify_url: Option<String>,
+
+    /// How to lay out hosts in tmux: a window each, or tiled panes in one
+    /// window so every host is in view
+    #[arg(long, value_enum, default_value_t)]
+    layout: run::Layout,
+
+    /// Most panes to tile in one window with --layout tiled; more hosts
+    /// than this get more windows
+    #[arg(long, value_name = "N", default_value_t = 16)]
+    max_panes: usize,
+
+    /// With --layout tiled, send what's typed in a window to every host in
+    /// it. `bdsh sync-panes` turns this on or off during a run.
+    #[arg(long)]
+    sync_panes: bool,
+
+    /// Order to start hosts in
+    #[arg(long, value_enum, default_value_t)]
+    order_by: schedule::OrderBy,
+
+    /// Seed for --order-by random, to shuffle hosts the same way again;
+    /// the seed used is kept in run.json
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Run hosts in groups by tag, e.g. `:db,:app,:web`, each group starting
+    /// once the one before has finished. Hosts with none of the tags go last.
+    #[arg(long, value_name = "TAGS", value_delimiter = ',', value_parser = schedule::parse_order_tag)]
+    order: Vec<String>,
+
+    /// Let up to N hosts fail in an --order group and still go on to the
+    /// next group
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "order")]
+    order_tolerance: usize,
+
+    /// Answer a prompt: when a host's output stops at PROMPT, type RESPONSE
+    /// and enter into its window, e.g. '[y/N]=y'. Only with tmux, and not
+    /// with --max-output-bytes, as prompts past the output kept aren't seen.
+    #[arg(long, value_name = "PROMPT=RESPONSE", conflicts_with_all = ["no_tmux", "batch", "stream", "group", "porcelain"])]
+    answer: Vec<answer::Answer>,
+
+    /// Stop starting hosts once N hosts, or N% of them, have failed. Needs
+    /// --order, as otherwise every host has started by then, or
+    /// --cancel-running
+    #[arg(long, value_name = "N[%]", value_parser = schedule::MaxFailures::parse)]
+    max_failures: Option<schedule::MaxFailures>,
+
+    /// Also kill hosts still running once --max-failures is reached
+    #[arg(long, requires = "max_failures")]
+    cancel_running: bool,
+
+    /// Give up on the whole run after this long (e.g. 90s, 15m, 2h), killing
+    /// the session and exiting nonzero, so unattended runs can't hang
+    #[arg(long, value_name = "DURATION", value_parser = run::parse_duration)]
+    max_run_time: Option<Duration>,
+
+    /// Name the tmux session, rather than with a random pair of words. The
+    /// name must not be in use.
+    #[arg(long, value_name = "NAME", value_parser = tmux::parse_session_name)]
+    session: Option<String>,
+
+    /// Name each host's window (its pane, with --layout tiled) from this
+    /// template: {host}, {n} for the host's number in the run, and {status},
+    /// which is running until the host finishes, then ok or its exit code.
+    /// Windows are kept open once finished when {status} is used.
+    #[arg(long, value_name = "TEMPLATE")]
+    window_name: Option<String>,
+
+    /// Start the session and every host, print how to attach, and exit
+    /// rather than waiting, e.g. for a long run over a flaky connection.
+    /// Nothing is left to order hosts, answer prompts, or report the end.
+    #[arg(long, conflicts_with_all = [
+        "no_tmux", "batch", "stream", "group", "porcelain", "order", "answer", "max_failures",
+        "max_run_time", "metrics_file", "notify_url",
+    ])]
+    detach: bool,
+
+    /// Check every host resolves in DNS before starting anything
+    #[arg(long)]
+    resolve_dns: bool,
+
+    /// Directory to write output to, defaults to a new temp directory
+    #[arg(short, long)]
+    output_dir: Option<PathBuf>,
+
+    /// Config file to read defaults from, instead of ~/.config/bdsh/config.toml
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Use the settings in the config's [profile.NAME] section over its defaults
+    #[arg(short, long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Run the command via sudo, as root or the given user. Forces a tty so
+    /// sudo can prompt for a password in the host's window, unless
+    /// --sudo-stdin.
+    #[arg(
+        long,
+        value_name = "USER",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "root"
+    )]
+    sudo: Option<String>,
+
+    /// Ask for the sudo password once (or get it from --secret-cmd) and
+    /// pipe it to sudo -S on every host, hiding it in the captured output.
+    /// The command gets no input.
+    #[arg(long, requires = "sudo")]
+    sudo_stdin: bool,
+
+    /// Run the command with LC_ALL set to this locale (C if not given), so
+    /// output is comparable across hosts with different default locales
+    #[arg(
+        long,
+        value_name = "LOCALE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "C"
+    )]
+    locale: Option<String>,
+
+    /// Export a variable for the command, with the value given or taken from
+    /// our own environment. May be repeated.
+    #[arg(short, long, value_name = "KEY[=VALUE]", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+
+    /// Export the variables in a file, one KEY[=VALUE] per line. --env
+    /// takes precedence.
+    #[arg(long, value_name = "FILE")]
+    env_file: Option<PathBuf>,
+
+    /// Reach hosts through this jump host, as for `ssh -J`. A `jump=` in the
+    /// hosts file takes precedence.
+    #[arg(short = 'J', long, value_name = "[USER@]HOST")]
+    jump: Option<String>,
+
+    /// How to run the command on hosts. With kubectl hosts are pods, with
+    /// docker or podman they are containers, and with ssm they are EC2
+    /// instance ids; ssh options don't apply to those. Defaults to ssh.
+    #[arg(long, value_enum)]
+    backend: Option<Backend>,
+
+    /// Command to use in place of ssh, e.g. "ssh -F ./ssh_config" or "tsh ssh".
+    /// It is run by sh, so may carry its own arguments.
+    #[arg(long, value_name = "COMMAND")]
+    ssh_cmd: Option<String>,
+
+    /// Extra argument for ssh, ahead of the host. May be repeated.
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    ssh_arg: Vec<String>,
+
+    /// Extra ssh option for every host, as for `ssh -o`. May be repeated.
+    #[arg(long, value_name = "KEY=VALUE")]
+    ssh_opt: Vec<String>,
+
+    /// Ask once for a key passphrase or password and answer every host's
+    /// ssh with it, through SSH_ASKPASS (needs OpenSSH 8.4)
+    #[arg(long, overrides_with = "no_askpass")]
+    askpass: bool,
+
+    /// Don't use --askpass, even if config says to
+    #[arg(long)]
+    no_askpass: bool,
+
+    /// Get the --askpass secret from the output of CMD, e.g. a keychain
+    /// lookup, rather than asking for it
+    #[arg(long, value_name = "CMD")]
+    secret_cmd: Option<String>,
+
+    /// Forward the ssh agent to every host
+    #[arg(short = 'A', long, overrides_with = "no_forward_agent")]
+    forward_agent: bool,
 
-    // create a temp dir to work in, for now use argv[1]
+    /// Don't forward the ssh agent, even if config says to
+    #[arg(long)]
+    no_forward_agent: bool,
 
-    // start control tmux against socket in temp dir
+    /// How to treat unknown or changed host keys, defaults to ssh's own setting
+    #[arg(long, value_enum)]
+    host_key_policy: Option<HostKeyPolicy>,
 
+    /// Watch an output directory, used when bdsh invokes itself inside tmux
+    #[arg(long, hide = true, exclusive = true)]
+    watch: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Manage the hosts file
+    Hosts {
+        /// Hosts file to edit, defaults to ~/.config/bdsh/hosts
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        #[command(subcommand)]
+        action: HostsAction,
+    },
+
+    /// Run a session against fake local hosts, to try bdsh out without any
+    /// real hosts
+    Demo {
+        /// How many fake hosts to run
+        #[arg(short = 'n', long, default_value_t = 6, value_parser = clap::value_parser!(u16).range(1..))]
+        hosts: u16,
+
+        /// Directory to write output to, defaults to a new temp directory
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+
+        /// Don't ask before running, as for a run
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Show how long hosts took in a run, from its output directory
+    Stats { output_dir: PathBuf },
+
+    /// List recent runs
+    History {
+        /// How many runs to list
+        #[arg(short = 'n', long, default_value_t = 20)]
+        count: usize,
+
+        #[command(subcommand)]
+        action: Option<HistoryAction>,
+    },
+
+    /// Run a past run's command again, on the same hosts
+    Rerun {
+        /// Run id, as listed by `bdsh history`
+        #[arg(required_unless_present = "last")]
+        id: Option<String>,
+
+        /// Re-run the most recent run
+        #[arg(long, conflicts_with = "id")]
+        last: bool,
+
+        /// Only the hosts which failed or didn't finish
+        #[arg(long)]
+        failed_only: bool,
+
+        /// Directory to write output to, defaults to a new temp directory
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+
+        /// Config file whose guards and confirm-over apply, instead of
+        /// ~/.config/bdsh/config.toml
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// Use the config's [profile.NAME] section over its defaults
+        #[arg(short, long, value_name = "NAME")]
+        profile: Option<String>,
+
+        /// Don't ask before running, as for a run
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Compare each host's output between two runs, e.g. before and after a
+    /// change
+    DiffRuns {
+        /// Output directory of the earlier run
+        first: PathBuf,
+        /// Output directory of the later run
+        second: PathBuf,
+
+        /// Only list which hosts changed, without the diffs
+        #[arg(short, long)]
+        summary: bool,
+    },
+
+    /// Reattach to a run still going in tmux, e.g. after your terminal died
+    Attach {
+        /// The run's output directory, or its session name as listed by
+        /// `bdsh history`
+        run: String,
+    },
+
+    /// Send what's typed in a tiled run's windows to every host in them, or
+    /// stop with --off
+    SyncPanes {
+        /// Output directory of the run
+        output_dir: PathBuf,
+
+        /// Go back to typing into one host at a time
+        #[arg(long)]
+        off: bool,
+    },
+
+    /// Copy stdin to stdout and to a capped log, used in host windows
+    #[command(hide = true)]
+    Capture {
+        #[arg(long)]
+        max_bytes: Option<u64>,
+        /// hide the run's secret, from BDSH_SECRET
+        #[arg(long)]
+        scrub: bool,
+        log: PathBuf,
+    },
+
+    /// Print help, or the full grammar of a topic
+    Help {
+        #[arg(value_enum)]
+        topic: Option<HelpTopic>,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+enum HelpTopic {
+    /// Host specs: hosts, files, and tag filters
+    Filters,
+    /// The hosts file
+    HostsFormat,
+    /// The --porcelain output format
+    Porcelain,
+}
+
+#[derive(Subcommand, Debug)]
+enum HistoryAction {
+    /// Watch a past run's output directory, if it is still there
+    Show { id: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum HostsAction {
+    /// Add or remove tags on a host
+    #[command(subcommand)]
+    Tag(TagAction),
+}
+
+#[derive(Subcommand, Debug)]
+enum TagAction {
+    /// Add tags to a host, adding the host if it isn't in the file
+    Add {
+        host: String,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from a host
+    Remove {
+        host: String,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+}
+
+fn main() -> Result<()> {
     // fire up tmux instance in foreground in "watch this directory" mode
 
     // Run commands a la `ssh freki $command | tee $bdsh_tmp/$host/out.log`
     // so that we capture output and still get the nice tmux experience if input is needed
 
-    //
-
-    let args: Vec<String> = env::args().collect();
-    let cmd = args.first().unwrap();
-    if args.len() == 2 {
+    let cli = Cli::parse();
+    match cli.mode {
+        Some(Mode::Hosts { file, action }) => return hosts_command(file, action),
+        Some(Mode::Demo {
+            hosts,
+            output_dir,
+            yes,
+        }) => {
+            let transport = Transport {
+                backend: Backend::Local,
+                ..Default::default()
+            };
+            let run = run::Run {
+                command: "bdsh demo".into(),
+                hosts: "demo".into(),
+                ..Default::default()
+            };
+            return run_session(
+                demo::hosts(hosts.into()),
+                &transport,
+                demo::SCRIPT,
+                run,
+                output_dir,
+                &config::load(None)?,
+                yes,
+            );
+        }
+        Some(Mode::Stats { output_dir }) => {
+            let run = run::Run::read(&output_dir)
+                .with_context(|| format!("no bdsh run in {}", output_dir.display()))?;
+            print!("{}", run::Timing::collect(&output_dir, &run).report());
+            return Ok(());
+        }
+        Some(Mode::History { count, action }) => return history_command(count, action),
+        Some(Mode::Rerun {
+            id,
+            last,
+            failed_only,
+            output_dir,
+            config,
+            profile,
+            yes,
+        }) => {
+            let config = load_config(config.as_deref(), profile.as_deref())?;
+            return rerun(id, last, failed_only, output_dir, &config, yes);
+        }
+        Some(Mode::DiffRuns {
+            first,
+            second,
+            summary,
+        }) => return diff_runs(&first, &second, summary),
+        Some(Mode::Attach { run }) => return attach(&run),
+        Some(Mode::SyncPanes { output_dir, off }) => return sync_panes(&output_dir, !off),
+        Some(Mode::Capture {
+            max_bytes,
+            scrub,
+            log,
+        }) => {
+            let log = fs::File::create(log)?;
+            let secret = if scrub {
+                env::var(secret::SECRET_VAR).unwrap_or_default()
+            } else {
+                String::new()
+            };
+            let input = capture::Scrub::new(std::io::stdin(), &secret);
+            let max_bytes = max_bytes.unwrap_or(u64::MAX);
+            capture::capture(input, std::io::stdout(), log, max_bytes)?;
+            return Ok(());
+        }
+        Some(Mode::Help { topic }) => {
+            match topic {
+                Some(HelpTopic::Filters) => print!("{}", hosts::spec_help()),
+                Some(HelpTopic::HostsFormat) => print!("{}", hosts::hosts_file_help()),
+                Some(HelpTopic::Porcelain) => print!("{}", report::porcelain_help()),
+                None => Cli::command().print_help()?,
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+    if let Some(dir) = &cli.watch {
         // invoked from self inside tmux
-        println!("sleeping for 10, C-c to terminate early");
-        std::thread::sleep(std::time::Duration::from_secs(10));
-        exit(0);
+        watch(dir);
+    }
+
+    let config = load_config(cli.config.as_deref(), cli.profile.as_deref())?;
+    let backend = cli.backend.or(config.backend).unwrap_or_default();
+    let jump = cli.jump.or(config.jump.clone());
+
+    let spec = cli.hosts.expect("clap requires hosts without --watch");
+    let mut names = vec![];
+    for glob in &cli.match_glob {
+        names.push(hosts::glob_regex(glob)?);
+    }
+    for re in &cli.match_re {
+        names.push(hosts::name_regex(re)?);
+    }
+    let resolved = hosts::resolve_hosts(&spec, &names)?;
+    for warning in &resolved.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    let mut hosts = resolved.hosts;
+    for host in hosts.iter_mut().filter(|host| host.jump.is_none()) {
+        host.jump.clone_from(&jump);
+    }
+    if hosts.is_empty() {
+        bail!("no hosts selected by '{}'", spec);
+    }
+    if cli.seed.is_some() && cli.order_by != schedule::OrderBy::Random {
+        bail!("--seed only applies with --order-by random");
+    }
+    let seed = match cli.order_by {
+        schedule::OrderBy::Random => Some(cli.seed.unwrap_or_else(rand::random)),
+        _ => None,
+    };
+    schedule::sort_hosts(&mut hosts, cli.order_by, seed.unwrap_or_default());
+    if cli.resolve_dns {
+        if backend != Backend::Ssh {
+            bail!("--resolve-dns only applies to the ssh backend");
+        }
+        let missing = hosts::unresolvable(&hosts);
+        if !missing.is_empty() {
+            bail!("unable to resolve: {}", missing.join(", "));
+        }
+    }
+    let mut command = cli.command.join(" ");
+    let mut vars = vec![];
+    if let Some(locale) = &cli.locale {
+        vars.push(("LC_ALL".to_string(), locale.clone()));
+    }
+    if let Some(path) = &cli.env_file {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        vars.extend(
+            parse_env_file(&contents).map_err(|err| anyhow!("in {}: {}", path.display(), err))?,
+        );
+    }
+    // later exports win, so flags go last
+    vars.extend(cli.env.iter().cloned());
+    if !vars.is_empty() {
+        command = export_env(&vars, &command);
+    }
+    let mut ssh_options = cli
+        .host_key_policy
+        .or(config.host_key_policy)
+        .unwrap_or_default()
+        .ssh_options();
+    // ssh keeps the first value it sees for an option, so flags go first
+    for opt in cli.ssh_opt.iter().chain(&config.ssh_opts) {
+        ssh_options.push("-o".to_string());
+        ssh_options.push(opt.clone());
+    }
+    if switch(
+        cli.forward_agent,
+        cli.no_forward_agent,
+        config.forward_agent,
+    ) {
+        ssh_options.push("-A".to_string());
+    }
+    ssh_options.extend(cli.ssh_arg.iter().chain(&config.ssh_args).cloned());
+    if let Some(user) = &cli.sudo {
+        command = sudo_command(user, &command, cli.sudo_stdin);
+        if !cli.sudo_stdin {
+            ssh_options.push("-t".to_string());
+        }
+    }
+    let transport = Transport {
+        backend,
+        ssh_command: cli
+            .ssh_cmd
+            .or(config.ssh_cmd.clone())
+            .unwrap_or_else(|| "ssh".into()),
+        ssh_options,
+        secret_stdin: cli.sudo_stdin,
+        tags: config.tag.clone(),
+        ..Default::default()
+    };
+
+    let askpass = switch(cli.askpass, cli.no_askpass, config.askpass);
+    if cli.max_failures.is_some() && cli.order.is_empty() && !cli.cancel_running {
+        bail!("--max-failures needs --order or --cancel-running, as every host starts at once");
+    }
+    if cli.sync_panes && cli.layout != run::Layout::Tiled {
+        bail!("--sync-panes only applies with --layout tiled");
+    }
+    if askpass && backend != Backend::Ssh {
+        bail!("--askpass only applies to the ssh backend");
+    }
+    // flags first, as the first matching answer is used
+    let mut answers = cli.answer;
+    for answer in &config.answers {
+        answers.push(
+            answer
+                .parse()
+                .map_err(|err| anyhow!("in config: {}", err))?,
+        );
+    }
+    let run = run::Run {
+        command: cli.command.join(" "),
+        hosts: spec,
+        max_output_bytes: cli.max_output_bytes,
+        compress: switch(cli.compress, cli.no_compress, config.compress),
+        bell: switch(cli.bell, cli.no_bell, config.bell),
+        max_run_time: cli.max_run_time,
+        metrics_file: cli.metrics_file,
+        notify_url: cli.notify_url,
+        seed,
+        order: cli.order,
+        order_tolerance: cli.order_tolerance,
+        max_failures: cli.max_failures,
+        cancel_running: cli.cancel_running,
+        answers,
+        askpass,
+        secret_cmd: cli.secret_cmd.or(config.secret_cmd.clone()),
+        layout: cli.layout,
+        max_panes: cli.max_panes,
+        sync_panes: cli.sync_panes,
+        detach: cli.detach,
+        session: cli.session.unwrap_or_default(),
+        window_name: cli.window_name.or(config.window_name.clone()),
+        output: if cli.porcelain {
+            run::Output::Porcelain
+        } else if cli.group {
+            run::Output::Group
+        } else if cli.stream {
+            run::Output::Stream
+        } else if cli.batch {
+            run::Output::Batch
+        } else if cli.no_tmux {
+            run::Output::Summary
+        } else {
+            run::Output::Tmux
+        },
+        ..Default::default()
+    };
+    // answers look for prompts in out.log, which stops growing once capped
+    if !run.answers.is_empty() && run.max_output_bytes.is_some() && run.output == run::Output::Tmux
+    {
+        bail!("answering prompts doesn't work with --max-output-bytes");
+    }
+    let output_dir = cli.output_dir.or(config.output_dir.clone());
+    run_session(
+        hosts, &transport, &command, run, output_dir, &config, cli.yes,
+    )
+}
+
+/// Run `command` on every host in a new tmux session, and attach to it.
+/// `run` describes the run as the user gave it, and gets the session filled in.
+/// `config`'s guards and confirm-over are checked first, unless `yes`.
+fn run_session(
+    hosts: Vec<Host>,
+    transport: &Transport,
+    command: &str,
+    mut run: run::Run,
+    output_dir: Option<PathBuf>,
+    config: &config::Config,
+    yes: bool,
+) -> Result<()> {
+    if hosts.is_empty() {
+        bail!("no hosts to run on");
+    }
+    check_before_run(config, yes, &run.command, &hosts)?;
+    let secret = (run.askpass || transport.secret_stdin)
+        .then(|| secret::Secret::obtain(run.secret_cmd.as_deref(), "Password: "))
+        .transpose()?;
+    let started = Instant::now();
+    let name = if run.session.is_empty() {
+        Generator::default().next().unwrap()
+    } else {
+        run.session.clone()
+    };
+    if !run.session.is_empty() && run.output == run::Output::Tmux {
+        let exists = Command::new("tmux")
+            .args(["has-session", "-t", &tmux::exact(&name)])
+            .stderr(std::process::Stdio::null())
+            .status()?
+            .success();
+        if exists {
+            bail!("tmux session {} already exists", name);
+        }
+    }
+    let output_dir = output_dir.unwrap_or_else(|| {
+        // a name given may well be used again, so keep each run apart
+        let dir = if run.session.is_empty() {
+            format!("bdsh-{}", name)
+        } else {
+            format!("bdsh-{}-{}", name, history::now())
+        };
+        env::temp_dir().join(dir)
+    });
+    fs::create_dir_all(&output_dir)?;
+    let mut transport = transport.clone();
+    if let Some(secret) = &secret {
+        transport.env = if run.askpass {
+            secret::askpass_env(secret, &secret::write_askpass(&output_dir)?)
+        } else {
+            secret::secret_env(secret)
+        };
+    }
+    let transport = &transport;
+    run.session = name.clone();
+    run.resolved = hosts.clone();
+    run.transport = transport.clone();
+    run.remote_command = command.into();
+    let entry = history::Entry {
+        id: name.clone(),
+        started: history::now(),
+        command: run.command.clone(),
+        hosts: run.hosts.clone(),
+        output_dir: output_dir.canonicalize()?,
+    };
+    if let Err(err) = history::record(&entry) {
+        eprintln!("warning: unable to record run in history: {}", err);
     }
 
-    let name = Generator::default().next().unwrap();
+    // a deadline too far off to represent is as good as none
+    let deadline = run.max_run_time.and_then(|max| started.checked_add(max));
+    if run.output != run::Output::Tmux {
+        return run_direct(
+            hosts,
+            transport,
+            command,
+            run,
+            &output_dir,
+            started,
+            deadline,
+        );
+    }
+
+    let exe = env::args().next().unwrap();
+    let capture = match (run.max_output_bytes, secret.is_some()) {
+        (None, false) => Capture::Tee,
+        (max_bytes, scrub) => Capture::Bdsh {
+            exe: exe.clone(),
+            max_bytes,
+            scrub,
+        },
+    };
+    let mut control = tmux::Control::start_session(
+        &name,
+        Some(format!(
+            "{} --watch {}",
+            shell_quote(&exe),
+            shell_quote(&output_dir.to_string_lossy())
+        )),
+    )?;
 
-    let mut control = tmux::Control::start_session(&name, Some(format!("{} {}", cmd, name)))?;
+    if run.bell {
+        // ring the attached terminal and show a message in the status line
+        control.set_option("bell-action", "any")?;
+        control.set_option("visual-bell", "both")?;
+    }
 
-    let mut ui_tmux = Command::new("tmux").args(["attach", "-t", &name]).spawn()?;
+    let mut ui_tmux = if run.detach {
+        None
+    } else {
+        Some(
+            Command::new("tmux")
+                .args(["attach", "-t", &tmux::exact(&name)])
+                .spawn()?,
+        )
+    };
 
-    dbg!(control.new_window("m0001", Some("sleep 4"))?);
-    dbg!(control.new_window("m0002", Some("sleep 4"))?);
-    dbg!(control.new_window("m0003", Some("sleep 4"))?);
-    dbg!(control.new_window("m0004", Some("sleep 4"))?);
-    dbg!(control.new_window("m0005", Some("sleep 4"))?);
-    dbg!(control.new_window("m0006", Some("sleep 4"))?);
+    // with --order, each group starts once the one before it has finished
+    let groups = schedule::groups(hosts, &run.order);
+    let last = groups.len() - 1;
+    let max_failures = run.max_failures.map(|max| max.limit(run.resolved.len()));
+    let failed = |dirs: &[PathBuf]| {
+        dirs.iter()
+            .filter(|dir| run::HostTiming::read(dir).status.is_some_and(|s| s != 0))
+            .count()
+    };
+    let mut answerer = answer::Answerer::new(run.answers.clone());
+    // hosts whose window names show how they finished
+    let mut labelled = HashSet::new();
+    let mut wait = Wait::Done;
+    let mut stopped = None;
+    let mut not_run = 0;
+    let mut cancelled = 0;
+    // host directories of every host started so far
+    let mut started_dirs: Vec<PathBuf> = vec![];
+    for (n, group) in groups.into_iter().enumerate() {
+        if stopped.is_some() {
+            not_run += group.len();
+            continue;
+        }
+        let first = started_dirs.len();
+        let per_window = match run.layout {
+            run::Layout::Windows => 1,
+            run::Layout::Tiled => run.max_panes.max(1),
+        };
+        for hosts in group.chunks(per_window) {
+            let mut tiled: Option<tmux::Window> = None;
+            for host in hosts {
+                let job = Job::new(&output_dir, host.clone(), transport, command);
+                job.write_script()?;
+                let window_command = job.window_command(&capture, run.compress, run.bell);
+                let window = match &tiled {
+                    None => {
+                        control.new_window(&job.host.name, Some(&window_command), &transport.env)
+                    }
+                    Some(tiled) => control.split_window(
+                        tiled,
+                        &job.host.name,
+                        Some(&window_command),
+                        &transport.env,
+                    ),
+                }
+                .with_context(|| format!("run state is kept in {}", output_dir.display()))?;
+                if run.layout == run::Layout::Tiled {
+                    // retile as we go, so each split has room
+                    control.select_layout(&window, "tiled")?;
+                    tiled.get_or_insert(window.clone());
+                }
+                if let Some(template) = &run.window_name {
+                    if template.contains("{status}") {
+                        // keep the window once it ends, to show how it did
+                        control.set_window_option(window.id(), "remain-on-exit", "on")?;
+                    }
+                    let label =
+                        window_name(template, &job.host.name, started_dirs.len() + 1, "running");
+                    label_window(&mut control, run.layout, &window, &label)?;
+                }
+                run.windows
+                    .insert(job.host.name.clone(), window.id().into());
+                run.write(&output_dir)?;
+                started_dirs.push(job.root);
+            }
+            if let Some(tiled) = &tiled {
+                if run.window_name.is_none() {
+                    control.set_title(tiled, &hosts[0].name)?;
+                }
+                let name = match hosts.len() {
+                    1 => hosts[0].name.clone(),
+                    n => format!("{} +{}", hosts[0].name, n - 1),
+                };
+                control.rename_window(tiled, &name)?;
+                control.set_window_option(tiled.id(), "pane-border-status", "top")?;
+                control.set_window_option(tiled.id(), "pane-border-format", " #{pane_title} ")?;
+                if run.sync_panes {
+                    control.set_window_option(tiled.id(), "synchronize-panes", "on")?;
+                }
+            }
+        }
+        let Some(ui_tmux) = &mut ui_tmux else {
+            // detached, so every host is started and that's all
+            control.kill()?;
+            println!(
+                "started {} hosts in tmux session {}",
+                started_dirs.len(),
+                name
+            );
+            println!("output is in {}", output_dir.display());
+            println!("attach with: bdsh attach {}", name);
+            return Ok(());
+        };
+        let group_dirs = &started_dirs[first..];
+        let tripped = || max_failures.is_some_and(|max| failed(&started_dirs) >= max);
+        wait = wait_for(ui_tmux, deadline, || {
+            answer_prompts(&mut control, &mut answerer, &started_dirs)?;
+            label_finished(&mut control, &run, &mut labelled, &started_dirs)?;
+            Ok(group_dirs.iter().all(|dir| dir.join(STATUS).exists()) || tripped())
+        })?;
+        if !matches!(wait, Wait::Done) {
+            stopped = Some("the session ended before every group ran".to_string());
+        } else if tripped() {
+            stopped = Some(format!("{} hosts failed", failed(&started_dirs)));
+            if run.cancel_running {
+                for dir in started_dirs.iter().filter(|dir| !dir.join(STATUS).exists()) {
+                    let host = dir.file_name().unwrap_or_default().to_string_lossy();
+                    if let Some(window) = control.window(&host).cloned() {
+                        match control.kill_window(&window) {
+                            Ok(()) => cancelled += 1,
+                            // it finished as we got to it
+                            Err(err) if err.is_gone() => {}
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                }
+            }
+        } else if n != last && failed(group_dirs) > run.order_tolerance {
+            stopped = Some(format!(
+                "{} hosts failed in group {}",
+                failed(group_dirs),
+                n + 1
+            ));
+        }
+    }
 
-    ui_tmux.wait()?;
+    let ui_tmux = ui_tmux
+        .as_mut()
+        .expect("detached runs return once hosts start");
+    if matches!(wait, Wait::Done) {
+        wait = wait_for(ui_tmux, deadline, || {
+            answer_prompts(&mut control, &mut answerer, &started_dirs)?;
+            label_finished(&mut control, &run, &mut labelled, &started_dirs)?;
+            Ok(false)
+        })?;
+    }
+    if matches!(wait, Wait::TimedOut) {
+        Command::new("tmux")
+            .args(["kill-session", "-t", &tmux::exact(&name)])
+            .status()?;
+        ui_tmux.wait()?;
+        control.kill()?;
+        finish_run(&output_dir, &run, started)?;
+        bail!(
+            "run took longer than --max-run-time, killed session {}; output is in {}",
+            name,
+            output_dir.display()
+        );
+    }
     control.kill()?;
+    finish_run(&output_dir, &run, started)?;
+    if let Some(reason) = stopped.filter(|_| not_run + cancelled > 0) {
+        bail!(
+            "{}, so {} hosts were not run and {} were cancelled; output is in {}",
+            reason,
+            not_run,
+            cancelled,
+            output_dir.display()
+        );
+    }
     println!("done");
     Ok(())
 }
 
-struct Job {
-    /// Directory this job executes in
-    root: PathBuf,
+/// How waiting on a tmux session ended
+enum Wait {
+    Done,
+    /// the attached client exited, e.g. on detach
+    Detached,
+    TimedOut,
+}
 
-    /// hostname to run command on
-    host: String,
+/// Wait until `done` says so, the attached tmux client exits, or `deadline`
+/// passes. `done` is asked every 100ms, and may do other work as it goes.
+fn wait_for(
+    ui_tmux: &mut std::process::Child,
+    deadline: Option<Instant>,
+    mut done: impl FnMut() -> Result<bool>,
+) -> Result<Wait> {
+    loop {
+        if ui_tmux.try_wait()?.is_some() {
+            return Ok(Wait::Detached);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            return Ok(Wait::TimedOut);
+        }
+        if done()? {
+            return Ok(Wait::Done);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
 
-    /// command to run
-    command: String,
+/// Answer any prompts hosts still running are waiting at, by typing into
+/// their windows
+fn answer_prompts(
+    control: &mut tmux::Control,
+    answerer: &mut answer::Answerer,
+    host_dirs: &[PathBuf],
+) -> Result<()> {
+    for dir in host_dirs.iter().filter(|dir| !dir.join(STATUS).exists()) {
+        let Some(response) = answerer.check(dir) else {
+            continue;
+        };
+        let host = dir.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(window) = control.window(&host).cloned() {
+            match control.send_line(&window, response) {
+                // it finished before the answer got there, so needs none
+                Err(err) if err.is_gone() => {}
+                result => result?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name the window of each host which has finished since last time with
+/// `--window-name`, now that its status is known
+fn label_finished(
+    control: &mut tmux::Control,
+    run: &run::Run,
+    labelled: &mut HashSet<PathBuf>,
+    host_dirs: &[PathBuf],
+) -> Result<()> {
+    let Some(template) = run
+        .window_name
+        .as_deref()
+        .filter(|t| t.contains("{status}"))
+    else {
+        return Ok(());
+    };
+    for (n, dir) in host_dirs.iter().enumerate() {
+        if labelled.contains(dir) {
+            continue;
+        }
+        let status = match run::HostTiming::read(dir).status {
+            None => continue,
+            Some(0) => "ok".to_string(),
+            Some(code) => format!("exit {}", code),
+        };
+        let host = dir.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(window) = control.window(&host).cloned() {
+            let label = window_name(template, &host, n + 1, &status);
+            label_window(control, run.layout, &window, &label)?;
+        }
+        labelled.insert(dir.clone());
+    }
+    Ok(())
+}
+
+/// Show `label` as the name of a host's window, or the title of its pane
+/// when tiled
+fn label_window(
+    control: &mut tmux::Control,
+    layout: run::Layout,
+    window: &tmux::Window,
+    label: &str,
+) -> Result<()> {
+    match layout {
+        run::Layout::Windows => control.rename_window(window, label)?,
+        run::Layout::Tiled => control.set_title(window, label)?,
+    }
+    Ok(())
+}
+
+/// Record how a run went once it ends: timing.json, and metrics or a
+/// notification if asked for
+fn finish_run(output_dir: &Path, run: &run::Run, started: Instant) -> Result<()> {
+    let timing = run::Timing::collect(output_dir, run);
+    timing.write(output_dir)?;
+    if let Some(url) = &run.notify_url {
+        let summary = notify::summary(run, &timing, output_dir, started.elapsed());
+        // the run itself went fine, so don't fail it over this
+        if let Err(err) = notify::post(url, &summary) {
+            eprintln!("warning: unable to notify {}: {}", url, err);
+        }
+    }
+    if let Some(path) = &run.metrics_file {
+        let metrics = run::metrics(&timing, started.elapsed(), history::now());
+        run::write_metrics(path, &metrics)
+            .with_context(|| format!("unable to write metrics to {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// A setting with a flag to turn it on, one to turn it off, and maybe a
+/// value from config, which either flag overrides
+fn switch(on: bool, off: bool, config: Option<bool>) -> bool {
+    if on || off {
+        on
+    } else {
+        config.unwrap_or(false)
+    }
+}
+
+/// Config from `file`, or the default config file, with `profile` applied
+fn load_config(file: Option<&Path>, profile: Option<&str>) -> Result<config::Config> {
+    let config = config::load(file)?;
+    Ok(match profile {
+        Some(profile) => config.with_profile(profile)?,
+        None => config,
+    })
+}
+
+/// Refuse commands guard rules block, and unless `yes`, ask before running
+/// commands other guards match, or on more than `confirm-over` hosts
+fn check_before_run(
+    config: &config::Config,
+    yes: bool,
+    command: &str,
+    hosts: &[Host],
+) -> Result<()> {
+    for guard in guard::check(&config.guard, command, hosts)? {
+        match guard.action {
+            guard::Action::Block => bail!("refusing to run: {}", guard.describe()),
+            guard::Action::Confirm if yes => {}
+            guard::Action::Confirm => {
+                let answer = ask(&format!("{}; run it anyway? [y/N]", guard.describe()))?;
+                if !matches!(answer.as_deref(), Some("y" | "Y" | "yes")) {
+                    bail!("not confirmed, nothing was run");
+                }
+            }
+        }
+    }
+    if config.confirm_over.is_some_and(|max| hosts.len() > max) && !yes {
+        let count = hosts.len().to_string();
+        let answer = ask(&format!(
+            "about to run on {} hosts; type {} to continue:",
+            count, count
+        ))?;
+        if answer.as_deref() != Some(count.as_str()) {
+            bail!("not confirmed, nothing was run (use --yes to skip this check)");
+        }
+    }
+    Ok(())
+}
+
+/// Ask a question on the terminal, or get no answer without a terminal to
+/// ask on
+fn ask(question: &str) -> Result<Option<String>> {
+    if !io::stdin().is_terminal() {
+        return Ok(None);
+    }
+    eprint!("{} ", question);
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(Some(answer.trim().to_string()))
+}
+
+/// Run the hosts' commands as child processes, reporting each host as it
+/// finishes, a group at a time with `--order`. Fails if any host does.
+fn run_direct(
+    hosts: Vec<Host>,
+    transport: &Transport,
+    command: &str,
+    run: run::Run,
+    output_dir: &Path,
+    started: Instant,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let groups = schedule::groups(hosts, &run.order);
+    let sizes: Vec<usize> = groups.iter().map(Vec::len).collect();
+    let mut jobs = vec![];
+    for host in groups.into_iter().flatten() {
+        let job = Job::new(output_dir, host, transport, command);
+        job.write_script()?;
+        jobs.push(job);
+    }
+    run.write(output_dir)?;
+    let names: Vec<&str> = jobs.iter().map(|job| job.host.name.as_str()).collect();
+    let mut reporter = report::Reporter::new(run.output, transport.backend, output_dir, &names);
+    let max_failures = run.max_failures.map(|max| max.limit(jobs.len()));
+    let mut failed = 0;
+    let mut timed_out = false;
+    let mut start = 0;
+    for size in sizes {
+        let cancel_after = max_failures
+            .filter(|_| run.cancel_running)
+            .map(|max| max - failed);
+        let finished = direct::run(
+            &jobs[start..start + size],
+            run.max_output_bytes,
+            run.compress,
+            deadline,
+            cancel_after,
+            run.output == run::Output::Stream,
+            |index, event| reporter.event(start + index, names[start + index], event),
+        )?;
+        let group_failed = finished.statuses.iter().filter(|s| **s != Some(0)).count();
+        failed += group_failed;
+        start += size;
+        timed_out = finished.timed_out;
+        let tripped = max_failures.is_some_and(|max| failed >= max);
+        if timed_out || tripped || group_failed > run.order_tolerance {
+            break;
+        }
+    }
+    for name in &names[start..] {
+        reporter.skipped(name);
+    }
+    reporter.finish(&names[..start]);
+    finish_run(output_dir, &run, started)?;
+    let not_run = match jobs.len() - start {
+        0 => String::new(),
+        n => format!(", {} not run", n),
+    };
+    if timed_out {
+        bail!(
+            "run took longer than --max-run-time{}; output is in {}",
+            not_run,
+            output_dir.display()
+        );
+    }
+    if failed > 0 {
+        bail!(
+            "{} of {} hosts failed{}; output is in {}",
+            failed,
+            jobs.len(),
+            not_run,
+            output_dir.display()
+        );
+    }
+    if run.output == run::Output::Summary {
+        println!("output is in {}", output_dir.display());
+    }
+    Ok(())
+}
+
+/// Watch an output directory; for now just a placeholder
+fn watch(_dir: &Path) -> ! {
+    println!("sleeping for 10, C-c to terminate early");
+    thread::sleep(Duration::from_secs(10));
+    exit(0);
+}
+
+fn history_command(count: usize, action: Option<HistoryAction>) -> Result<()> {
+    let entries = history::entries()?;
+    match action {
+        None => {
+            for entry in entries.iter().rev().take(count) {
+                let run = run::Run::read(&entry.output_dir);
+                let outcome = match &run {
+                    Ok(run) => {
+                        let timing = run::Timing::collect(&entry.output_dir, run);
+                        let ok = timing
+                            .hosts
+                            .values()
+                            .filter(|h| h.status == Some(0))
+                            .count();
+                        let failed = timing
+                            .hosts
+                            .values()
+                            .filter(|h| h.status.is_some_and(|s| s != 0))
+                            .count();
+                        let unreachable = timing.hosts.values().filter(|h| h.unreachable).count();
+                        match unreachable {
+                            0 => format!("{} ok, {} failed of {}", ok, failed, timing.hosts.len()),
+                            _ => format!(
+                                "{} ok, {} failed ({} unreachable) of {}",
+                                ok,
+                                failed,
+                                unreachable,
+                                timing.hosts.len()
+                            ),
+                        }
+                    }
+                    Err(_) => "output gone".into(),
+                };
+                println!(
+                    "{:<24} {:>8}  {:<24} {:<24} {}",
+                    entry.id,
+                    history::ago(entry.started),
+                    outcome,
+                    entry.hosts,
+                    entry.command
+                );
+            }
+        }
+        Some(HistoryAction::Show { id }) => {
+            let entry = entries
+                .iter()
+                .rev()
+                .find(|entry| entry.id == id)
+                .ok_or_else(|| anyhow!("no run {} in history", id))?;
+            if !entry.output_dir.is_dir() {
+                bail!(
+                    "output of run {} is gone from {}",
+                    id,
+                    entry.output_dir.display()
+                );
+            }
+            watch(&entry.output_dir);
+        }
+    }
+    Ok(())
+}
+
+/// Run a past run again, into a new output directory
+fn rerun(
+    id: Option<String>,
+    last: bool,
+    failed_only: bool,
+    output_dir: Option<PathBuf>,
+    config: &config::Config,
+    yes: bool,
+) -> Result<()> {
+    let entries = history::entries()?;
+    let entry = match (&id, last) {
+        (_, true) => entries.last(),
+        // names given with --session may have been used before
+        (Some(id), _) => entries.iter().rev().find(|entry| &entry.id == id),
+        (None, false) => None,
+    }
+    .ok_or_else(|| anyhow!("no such run in history"))?;
+    let original = run::Run::read(&entry.output_dir)
+        .with_context(|| format!("output of run {} is gone", entry.id))?;
+    if original.resolved.is_empty() {
+        bail!(
+            "run {} didn't record its hosts, so can't be re-run",
+            entry.id
+        );
+    }
+
+    let mut hosts = original.resolved.clone();
+    if failed_only {
+        let timing = run::Timing::collect(&entry.output_dir, &original);
+        hosts.retain(|host| timing.hosts.get(&host.name).and_then(|h| h.status) != Some(0));
+        if hosts.is_empty() {
+            bail!("every host in run {} succeeded", entry.id);
+        }
+    }
+    let run = run::Run {
+        command: original.command.clone(),
+        hosts: original.hosts.clone(),
+        max_output_bytes: original.max_output_bytes,
+        compress: original.compress,
+        bell: original.bell,
+        order: original.order.clone(),
+        order_tolerance: original.order_tolerance,
+        max_failures: original.max_failures,
+        cancel_running: original.cancel_running,
+        answers: original.answers.clone(),
+        askpass: original.askpass,
+        secret_cmd: original.secret_cmd.clone(),
+        max_run_time: original.max_run_time,
+        metrics_file: original.metrics_file.clone(),
+        notify_url: original.notify_url.clone(),
+        output: original.output,
+        layout: original.layout,
+        max_panes: original.max_panes,
+        sync_panes: original.sync_panes,
+        window_name: original.window_name.clone(),
+        rerun_of: Some(entry.output_dir.clone()),
+        ..Default::default()
+    };
+    run_session(
+        hosts,
+        &original.transport,
+        &original.remote_command,
+        run,
+        output_dir,
+        config,
+        yes,
+    )
+}
+
+/// Attach to the tmux session of a run, given by output directory or session
+/// name, bringing back its watch window if that has gone
+fn attach(target: &str) -> Result<()> {
+    let output_dir = if Path::new(target).join("run.json").is_file() {
+        Some(PathBuf::from(target))
+    } else {
+        history::entries()?
+            .into_iter()
+            .rev()
+            .find(|entry| entry.id == target)
+            .map(|entry| entry.output_dir)
+    };
+    let run = output_dir
+        .as_deref()
+        .and_then(|dir| run::Run::read(dir).ok());
+    let session = run.as_ref().map_or(target, |run| run.session.as_str());
+    let listed = Command::new("tmux")
+        .args([
+            "list-windows",
+            "-t",
+            &tmux::exact(session),
+            "-F",
+            "#{window_id}",
+        ])
+        .output()?;
+    if !listed.status.success() {
+        bail!("no tmux session {}, the run may have ended", session);
+    }
+
+    if let (Some(run), Some(dir)) = (&run, &output_dir) {
+        let hosts: BTreeSet<&str> = run.windows.values().map(String::as_str).collect();
+        let watching = String::from_utf8_lossy(&listed.stdout)
+            .lines()
+            .any(|id| !hosts.contains(id));
+        if !watching {
+            let exe = env::args().next().unwrap();
+            let mut control = tmux::Control::attach_session(session)?;
+            control.new_window(
+                "watch",
+                Some(&format!(
+                    "{} --watch {}",
+                    shell_quote(&exe),
+                    shell_quote(&dir.to_string_lossy())
+                )),
+                &[],
+            )?;
+            control.kill()?;
+        }
+    }
+
+    let status = Command::new("tmux")
+        .args(["attach", "-t", &tmux::exact(session)])
+        .status()?;
+    if !status.success() {
+        bail!("unable to attach to tmux session {}", session);
+    }
+    Ok(())
+}
+
+/// Turn synchronize-panes on or off in each window of a tiled run
+fn sync_panes(output_dir: &Path, on: bool) -> Result<()> {
+    let run = run::Run::read(output_dir)
+        .with_context(|| format!("no bdsh run in {}", output_dir.display()))?;
+    if run.layout != run::Layout::Tiled {
+        bail!("run {} isn't tiled, so has no panes to sync", run.session);
+    }
+    let windows: BTreeSet<&String> = run.windows.values().collect();
+    let mut control = tmux::Control::attach_session(&run.session)
+        .with_context(|| format!("session {} is gone", run.session))?;
+    for window in windows {
+        control.set_window_option(window, "synchronize-panes", if on { "on" } else { "off" })?;
+    }
+    control.kill()?;
+    Ok(())
+}
+
+fn diff_runs(first: &Path, second: &Path, summary: bool) -> Result<()> {
+    let changes = diff::diff_runs(first, second)?;
+    let changed = changes
+        .iter()
+        .filter(|(_, change)| *change != diff::Change::Same)
+        .count();
+    println!("{} of {} hosts differ", changed, changes.len());
+    for (host, change) in &changes {
+        match change {
+            diff::Change::Same => println!("  same      {}", host),
+            diff::Change::Changed { added, removed, .. } => {
+                println!("  changed   {} +{} -{}", host, added, removed)
+            }
+            diff::Change::OnlyInFirst => println!("  only in {} {}", first.display(), host),
+            diff::Change::OnlyInSecond => println!("  only in {} {}", second.display(), host),
+        }
+    }
+    if !summary {
+        for (_, change) in &changes {
+            if let diff::Change::Changed { diff, .. } = change {
+                print!("\n{}", diff);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn hosts_command(file: Option<PathBuf>, action: HostsAction) -> Result<()> {
+    let path = file
+        .or_else(hosts::default_hosts_file)
+        .ok_or_else(|| anyhow!("unable to find hosts file, pass --file"))?;
+    match action {
+        HostsAction::Tag(action) => {
+            let mut contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(err.into()),
+            };
+            match action {
+                TagAction::Add { host, tags } => {
+                    for tag in tags {
+                        contents = hosts::add_tag(&contents, &host, &tag)?;
+                    }
+                }
+                TagAction::Remove { host, tags } => {
+                    for tag in tags {
+                        contents = hosts::remove_tag(&contents, &host, &tag)?;
+                    }
+                }
+            }
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            hosts::write_hosts_file(&path, &contents)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_max_failures_skips_later_groups() {
+        let dir = env::temp_dir().join(format!("bdsh-max-failures-{}", std::process::id()));
+        let transport = Transport {
+            backend: Backend::Local,
+            ..Default::default()
+        };
+        let hosts = || {
+            vec![
+                Host {
+                    name: "a".into(),
+                    tags: vec!["first".into()],
+                    ..Default::default()
+                },
+                "b".parse().unwrap(),
+            ]
+        };
+        let run = |max_failures| run::Run {
+            order: vec!["first".into()],
+            order_tolerance: 1,
+            max_failures,
+            output: run::Output::Summary,
+            ..Default::default()
+        };
+        let command = r#"test "$BDSH_HOST" != a"#;
+
+        let result = run_direct(
+            hosts(),
+            &transport,
+            command,
+            run(None),
+            &dir,
+            Instant::now(),
+            None,
+        );
+        assert!(result.is_err());
+        assert!(dir.join("b").join(STATUS).exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let max_failures = Some(schedule::MaxFailures::Count(1));
+        let result = run_direct(
+            hosts(),
+            &transport,
+            command,
+            run(max_failures),
+            &dir,
+            Instant::now(),
+            None,
+        );
+        assert!(result.unwrap_err().to_string().contains("1 not run"));
+        assert!(!dir.join("b").join(STATUS).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,100 @@
+use crate::run::{Run, Timing};
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use thiserror::Error;
+
+type Result<T> = std::result::Result<T, NotifyError>;
+
+/// What `--notify-url` is sent when a run ends
+pub fn summary(run: &Run, timing: &Timing, output_dir: &Path, elapsed: Duration) -> Value {
+    let results: Vec<Value> = timing
+        .hosts
+        .iter()
+        .map(|(host, t)| {
+            json!({
+                "host": host,
+                "ok": t.status == Some(0),
+                "status": t.status,
+                "unreachable": t.unreachable,
+                "duration": t.duration,
+            })
+        })
+        .collect();
+    json!({
+        "session": run.session,
+        "command": run.command,
+        "hosts": run.hosts,
+        "output_dir": output_dir,
+        "duration": elapsed.as_secs_f64(),
+        "failed": timing.hosts.values().filter(|t| t.status != Some(0)).count(),
+        "results": results,
+    })
+}
+
+/// POST `body` as JSON to `url` with curl, which already knows about
+/// proxies and certificates
+pub fn post(url: &str, body: &Value) -> Result<()> {
+    let mut curl = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time", "30"])
+        .args(["-X", "POST", "-H", "Content-Type: application/json"])
+        .args(["--data-binary", "@-", "--output", "/dev/null", "--url", url])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(NotifyError::Curl)?;
+    let mut stdin = curl.stdin.take().unwrap();
+    stdin
+        .write_all(body.to_string().as_bytes())
+        .map_err(NotifyError::Curl)?;
+    drop(stdin);
+    let output = curl.wait_with_output().map_err(NotifyError::Curl)?;
+    if !output.status.success() {
+        return Err(NotifyError::Failed(
+            String::from_utf8_lossy(&output.stderr).trim().into(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum NotifyError {
+    #[error("unable to run curl: {0}")]
+    Curl(io::Error),
+
+    #[error("{0}")]
+    Failed(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run::HostTiming;
+
+    #[test]
+    fn test_summary() {
+        let run = Run {
+            command: "uptime".into(),
+            hosts: "web1,web2".into(),
+            ..Default::default()
+        };
+        let mut timing = Timing::default();
+        for (host, status) in [("web1", Some(0)), ("web2", Some(2))] {
+            let host_timing = HostTiming {
+                status,
+                duration: Some(3),
+                ..Default::default()
+            };
+            timing.hosts.insert(host.into(), host_timing);
+        }
+        let summary = summary(&run, &timing, Path::new("/tmp/x"), Duration::from_secs(4));
+        assert_eq!(summary["command"], "uptime");
+        assert_eq!(summary["failed"], 1);
+        assert_eq!(
+            summary["results"][1],
+            json!({"host": "web2", "ok": false, "status": 2, "unreachable": false, "duration": 3})
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use crate::direct::Event;
+use crate::job::{Backend, COMPRESSED_LOG, LOG};
+use crate::run::{has_log, read_log, HostTiming, Output};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+
+/// Prints what happens in a run without tmux, as its output mode asks
+pub struct Reporter<'a> {
+    /// where reports go, stdout but for tests
+    out: Box<dyn Write + 'a>,
+    output: Output,
+    backend: Backend,
+    output_dir: &'a Path,
+    /// color host prefixes, when printing to a terminal
+    colors: bool,
+    /// longest host name, to line up streamed output
+    width: usize,
+    /// hosts finished so far
+    done: usize,
+    /// hosts killed before they finished, and why
+    killed: HashMap<String, Status>,
+}
+
+impl<'a> Reporter<'a> {
+    pub fn new(
+        output: Output,
+        backend: Backend,
+        output_dir: &'a Path,
+        hosts: &[&str],
+    ) -> Reporter<'a> {
+        Reporter {
+            out: Box::new(io::stdout()),
+            output,
+            backend,
+            output_dir,
+            colors: io::stdout().is_terminal(),
+            width: hosts.iter().map(|host| host.len()).max().unwrap_or(0),
+            done: 0,
+            killed: HashMap::new(),
+        }
+    }
+
+    /// Report `event` for `host`, the `index`th host of the run
+    pub fn event(&mut self, index: usize, host: &str, event: Event) {
+        match event {
+            Event::Line(line) => self.line(index, host, &line),
+            Event::TimedOut => {
+                self.killed.insert(host.into(), Status::TimedOut);
+            }
+            Event::Cancelled => {
+                self.killed.insert(host.into(), Status::Cancelled);
+            }
+            Event::Done(status) => self.finished(host, status),
+        }
+    }
+
+    fn line(&mut self, index: usize, host: &str, line: &str) {
+        let prefix = format!("{:<width$}", format!("{}:", host), width = self.width + 1);
+        if self.colors {
+            self.say(format_args!(
+                "\x1b[{}m{}\x1b[0m {}",
+                31 + index % 6,
+                prefix,
+                line
+            ));
+        } else {
+            self.say(format_args!("{} {}", prefix, line));
+        }
+    }
+
+    fn finished(&mut self, host: &str, status: Option<i32>) {
+        let outcome = Status::new(
+            status,
+            self.backend.unreachable(status),
+            self.killed.get(host).copied(),
+        );
+        self.outcome(host, status, outcome);
+    }
+
+    /// Report a host which never started, as the run stopped first
+    pub fn skipped(&mut self, host: &str) {
+        self.outcome(host, None, Status::Skipped);
+    }
+
+    fn outcome(&mut self, host: &str, exit: Option<i32>, outcome: Status) {
+        self.done += 1;
+        let text = if self.colors {
+            format!("\x1b[{}m{}\x1b[0m", outcome.color(), outcome)
+        } else {
+            outcome.to_string()
+        };
+        match self.output {
+            Output::Batch => {
+                let tag = match outcome {
+                    Status::Ok => "SUCCESS",
+                    Status::Skipped => "SKIPPED",
+                    _ => "FAILURE",
+                };
+                let done = self.done;
+                self.say(format_args!("[{}] [{}] {}: {}", done, tag, host, text));
+                let log = self.host_output(host);
+                if !log.is_empty() {
+                    self.say(format_args!("{}", log.strip_suffix('\n').unwrap_or(&log)));
+                }
+            }
+            Output::Porcelain => {
+                let host_dir = self.output_dir.join(host);
+                let timing = HostTiming::read(&host_dir);
+                let mut log = host_dir.join(COMPRESSED_LOG);
+                if !log.exists() {
+                    log = host_dir.join(LOG);
+                }
+                let log = (outcome != Status::Skipped).then_some(log.as_path());
+                let line = porcelain_line(host, outcome, exit, timing.duration, log);
+                self.say(format_args!("{}", line));
+            }
+            // streamed or grouped output speaks for itself, unless something
+            // went wrong
+            Output::Stream | Output::Group if outcome != Status::Ok => {
+                eprintln!("{}: {}", host, text)
+            }
+            Output::Stream | Output::Group => {}
+            _ => self.say(format_args!("{}: {}", host, text)),
+        }
+    }
+
+    /// A host's output, or nothing if it has none. Output which can't be
+    /// read is said so on stderr, rather than passed off as no output.
+    fn host_output(&self, host: &str) -> String {
+        let host_dir = self.output_dir.join(host);
+        if !has_log(&host_dir) {
+            return String::new();
+        }
+        read_log(&host_dir).unwrap_or_else(|err| {
+            eprintln!("{}: unable to read output: {}", host, err);
+            String::new()
+        })
+    }
+
+    /// Print a line of the report; like println!, there's nothing to be done
+    /// if stdout is gone
+    fn say(&mut self, line: fmt::Arguments) {
+        let _ = writeln!(self.out, "{}", line);
+    }
+
+    /// Report the end of the run, once every host has finished
+    pub fn finish(&mut self, hosts: &[&str]) {
+        if self.output != Output::Group {
+            return;
+        }
+        let outputs = hosts
+            .iter()
+            .map(|host| (*host, self.host_output(host)))
+            .collect();
+        for (hosts, output) in group(outputs) {
+            let count = match hosts.len() {
+                1 => "1 host".to_string(),
+                n => format!("{} hosts", n),
+            };
+            self.say(format_args!("==== {} ({})", hosts.join(", "), count));
+            if !output.is_empty() {
+                self.say(format_args!(
+                    "{}",
+                    output.strip_suffix('\n').unwrap_or(&output)
+                ));
+            }
+        }
+    }
+}
+
+/// Group hosts by identical output, in the order each output first appears
+fn group(outputs: Vec<(&str, String)>) -> Vec<(Vec<&str>, String)> {
+    let mut groups: Vec<(Vec<&str>, String)> = vec![];
+    for (host, output) in outputs {
+        match groups.iter_mut().find(|(_, seen)| *seen == output) {
+            Some((hosts, _)) => hosts.push(host),
+            None => groups.push((vec![host], output)),
+        }
+    }
+    groups
+}
+
+/// One `--porcelain` line; see [`porcelain_help`] before changing anything
+fn porcelain_line(
+    host: &str,
+    outcome: Status,
+    exit: Option<i32>,
+    duration: Option<u64>,
+    log: Option<&Path>,
+) -> String {
+    let word = match outcome {
+        Status::Ok => "ok",
+        Status::Failed(_) | Status::Unreachable(_) => "failed",
+        Status::TimedOut | Status::Cancelled | Status::Killed => "killed",
+        Status::Skipped => "skipped",
+    };
+    let or_dash = |n: Option<String>| n.unwrap_or_else(|| "-".into());
+    format!(
+        "{}\t{}\t{}\t{}\t{}",
+        host,
+        word,
+        or_dash(exit.map(|s| s.to_string())),
+        or_dash(duration.map(|d| d.to_string())),
+        or_dash(log.map(|log| log.display().to_string()))
+    )
+}
+
+/// The `--porcelain` format, which scripts rely on, so must never change
+pub fn porcelain_help() -> &'static str {
+    r#"bdsh --porcelain prints one line per host, as each host finishes, in the
+order they finish. Fields are separated by a tab:
+
+    HOST  STATUS  EXIT  DURATION  OUTPUT
+
+    HOST      the host name, as resolved from the host spec
+    STATUS    ok, failed (nonzero exit), killed (no exit status, e.g.
+              by --max-run-time), or skipped (never started, as --order
+              or --max-failures stopped the run first)
+    EXIT      the command's exit code, or - if it has none
+    DURATION  whole seconds the command ran, or - if unknown
+    OUTPUT    path to the host's output, out.log or out.log.gz, or - if
+              it never started
+
+Nothing else is printed to stdout; errors go to stderr, and bdsh exits
+nonzero if any host failed. This format will not change between versions;
+new fields, if ever needed, will only be added at the end of the line.
+"#
+}
+
+/// How a host's command ended, as told in text output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    /// exited nonzero
+    Failed(i32),
+    /// never reached the host, see [`Backend::unreachable`]
+    Unreachable(i32),
+    /// killed for running past --max-run-time
+    TimedOut,
+    /// killed as --max-failures was reached
+    Cancelled,
+    /// killed by something else
+    Killed,
+    /// never started, as the run stopped first
+    Skipped,
+}
+
+impl Status {
+    /// The status of a job which exited with `exit`, or if it has no exit
+    /// status, was killed for reason `killed`
+    fn new(exit: Option<i32>, unreachable: bool, killed: Option<Status>) -> Status {
+        match exit {
+            Some(0) => Status::Ok,
+            Some(code) if unreachable => Status::Unreachable(code),
+            Some(code) => Status::Failed(code),
+            None => killed.unwrap_or(Status::Killed),
+        }
+    }
+
+    /// ANSI color for the status
+    fn color(&self) -> u8 {
+        match self {
+            Status::Ok => 32,
+            Status::Failed(_) | Status::Killed => 31,
+            Status::Unreachable(_) => 33,
+            Status::TimedOut => 35,
+            Status::Cancelled => 36,
+            Status::Skipped => 90,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Ok => write!(f, "ok"),
+            Status::Failed(code) => write!(f, "failed, exit status {}", code),
+            Status::Unreachable(code) => write!(f, "unreachable, exit status {}", code),
+            Status::TimedOut => write!(f, "timed out"),
+            Status::Cancelled => write!(f, "cancelled"),
+            Status::Killed => write!(f, "killed"),
+            Status::Skipped => write!(f, "skipped, never started"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_group() {
+        let groups = group(vec![
+            ("a", "up\n".into()),
+            ("b", "down\n".into()),
+            ("c", "up\n".into()),
+        ]);
+        assert_eq!(
+            groups,
+            vec![
+                (vec!["a", "c"], "up\n".to_string()),
+                (vec!["b"], "down\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(Status::new(Some(0), false, None), Status::Ok);
+        assert_eq!(Status::new(Some(255), true, None), Status::Unreachable(255));
+        let timed_out = Some(Status::TimedOut);
+        assert_eq!(Status::new(None, false, timed_out), Status::TimedOut);
+        assert_eq!(Status::new(None, false, None).to_string(), "killed");
+    }
+
+    #[test]
+    fn test_porcelain_line() {
+        let log = Path::new("/tmp/bdsh-x/web1/out.log");
+        assert_eq!(
+            porcelain_line("web1", Status::Failed(2), Some(2), Some(7), Some(log)),
+            "web1\tfailed\t2\t7\t/tmp/bdsh-x/web1/out.log"
+        );
+        assert_eq!(
+            porcelain_line("web2", Status::Killed, None, None, Some(log)),
+            "web2\tkilled\t-\t-\t/tmp/bdsh-x/web1/out.log"
+        );
+    }
+
+    #[test]
+    fn test_porcelain_stopped_partway() {
+        // a shared buffer, as the reporter owns its writer
+        #[derive(Clone, Default)]
+        struct Buffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let dir = Path::new("/nonexistent/bdsh-run");
+        let buffer = Buffer::default();
+        let mut reporter = Reporter::new(Output::Porcelain, Backend::Ssh, dir, &["a", "b", "c"]);
+        reporter.out = Box::new(buffer.clone());
+        reporter.event(0, "a", Event::Done(Some(1)));
+        reporter.skipped("b");
+        reporter.skipped("c");
+        drop(reporter);
+        let out = String::from_utf8(buffer.0.take()).unwrap();
+        assert_eq!(
+            out,
+            "a\tfailed\t1\t-\t/nonexistent/bdsh-run/a/out.log\n\
+             b\tskipped\t-\t-\t-\n\
+             c\tskipped\t-\t-\t-\n"
+        );
+    }
+}
@@ -0,0 +1,415 @@
+use crate::answer::Answer;
+use crate::hosts::Host;
+use crate::job::{Transport, COMPRESSED_LOG, FINISHED, LOG, STARTED, STATUS};
+use crate::schedule::MaxFailures;
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What a run is doing, kept in `run.json` in the output directory so that
+/// anything acting on the run later can find its way around
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Run {
+    /// tmux session the run is in
+    pub session: String,
+
+    /// the command as given, before sudo and the like are applied
+    pub command: String,
+
+    /// the host spec as given
+    pub hosts: String,
+
+    /// what the run does on each host, enough to run it again
+    pub resolved: Vec<Host>,
+    /// seed hosts were shuffled with, for `--order-by random`
+    pub seed: Option<u64>,
+    pub transport: Transport,
+    pub remote_command: String,
+
+    /// cap on each host's out.log, see `capture`
+    pub max_output_bytes: Option<u64>,
+
+    /// gzip each host's out.log once its output ends
+    pub compress: bool,
+
+    /// ring the bell in a host's tmux window when it fails
+    pub bell: bool,
+
+    /// how hosts are run and their output shown
+    pub output: Output,
+
+    /// how host windows are laid out in tmux
+    pub layout: Layout,
+
+    /// most panes in a window with `Layout::Tiled`
+    pub max_panes: usize,
+
+    /// send keys typed in a tiled window to all its panes
+    pub sync_panes: bool,
+
+    /// start every host and leave the session running without us
+    pub detach: bool,
+
+    /// template for host window names, see `job::window_name`
+    pub window_name: Option<String>,
+
+    /// tags of the groups hosts run in, one group after another
+    pub order: Vec<String>,
+
+    /// hosts which may fail in a group before later groups are called off
+    pub order_tolerance: usize,
+
+    /// failures across the run which stop it starting more hosts
+    pub max_failures: Option<MaxFailures>,
+
+    /// kill hosts still running once `max_failures` is reached
+    pub cancel_running: bool,
+
+    /// prompts to answer, in tmux
+    pub answers: Vec<Answer>,
+
+    /// ask once for a secret and give it to ssh on every host
+    pub askpass: bool,
+
+    /// command which prints the secret, rather than asking for it
+    pub secret_cmd: Option<String>,
+
+    /// how long the run may take before it is given up on
+    pub max_run_time: Option<Duration>,
+
+    /// where to write Prometheus metrics when the run ends
+    pub metrics_file: Option<PathBuf>,
+
+    /// where to POST a summary when the run ends
+    pub notify_url: Option<String>,
+
+    /// output directory of the run this one re-ran
+    pub rerun_of: Option<PathBuf>,
+
+    /// tmux window id for each host, by host name
+    pub windows: BTreeMap<String, String>,
+}
+
+impl Run {
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        write_json(dir, "run.json", self)
+    }
+
+    pub fn read(dir: &Path) -> io::Result<Run> {
+        let json = fs::read_to_string(dir.join("run.json"))?;
+        serde_json::from_str(&json).map_err(io::Error::other)
+    }
+}
+
+/// How a run is presented. All but `Tmux` run hosts as child processes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Output {
+    /// a window per host in a tmux session
+    #[default]
+    Tmux,
+    /// a line per host as it finishes
+    Summary,
+    /// each host's output in one piece as it finishes
+    Batch,
+    /// every line as it arrives, prefixed by its host
+    Stream,
+    /// each distinct output once, with its hosts, after all hosts finish
+    Group,
+    /// a stable line per host as it finishes, for scripts
+    Porcelain,
+}
+
+/// How hosts are laid out in a tmux session
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// a window per host
+    #[default]
+    Windows,
+    /// a pane per host, tiled in as few windows as the pane cap allows
+    Tiled,
+}
+
+/// Read a host's output from its directory, whether compressed or not.
+/// Output needn't be UTF-8, so anything else is replaced rather than lost.
+pub fn read_log(host_dir: &Path) -> io::Result<String> {
+    let bytes = match fs::read(host_dir.join(LOG)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let mut log = vec![];
+            GzDecoder::new(fs::File::open(host_dir.join(COMPRESSED_LOG))?).read_to_end(&mut log)?;
+            log
+        }
+        result => result?,
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Whether a host directory has output, compressed or not
+pub fn has_log(host_dir: &Path) -> bool {
+    host_dir.join(LOG).is_file() || host_dir.join(COMPRESSED_LOG).is_file()
+}
+
+/// How long each host took, written to `timing.json` when a run ends
+#[derive(Debug, Default, Serialize)]
+pub struct Timing {
+    pub hosts: BTreeMap<String, HostTiming>,
+
+    /// percentiles of the durations of the hosts which finished, in seconds
+    pub p50: Option<u64>,
+    pub p90: Option<u64>,
+    pub p99: Option<u64>,
+    pub max: Option<u64>,
+}
+
+/// Times are unix seconds, and missing if the host didn't get that far
+#[derive(Debug, Default, Serialize)]
+pub struct HostTiming {
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    pub duration: Option<u64>,
+    pub status: Option<i32>,
+    /// the command failed because the host couldn't be reached
+    pub unreachable: bool,
+}
+
+impl HostTiming {
+    /// Read the files a host's script wrote in its directory
+    pub fn read(host_dir: &Path) -> HostTiming {
+        let read = |name: &str| -> Option<String> {
+            let text = fs::read_to_string(host_dir.join(name)).ok()?;
+            Some(text.trim().to_string())
+        };
+        let started = read(STARTED).and_then(|t| t.parse().ok());
+        let finished = read(FINISHED).and_then(|t| t.parse().ok());
+        HostTiming {
+            started,
+            finished,
+            duration: finished
+                .zip(started)
+                .map(|(end, start): (u64, u64)| end.saturating_sub(start)),
+            status: read(STATUS).and_then(|t| t.parse().ok()),
+            unreachable: false,
+        }
+    }
+}
+
+impl Timing {
+    /// Gather timing from the files each host's script wrote in `dir`
+    pub fn collect(dir: &Path, run: &Run) -> Timing {
+        let mut timing = Timing::default();
+        let names = run.resolved.iter().map(|host| &host.name);
+        // runs from before hosts were recorded only have windows
+        for host in names.chain(run.windows.keys()) {
+            let mut host_timing = HostTiming::read(&dir.join(host));
+            host_timing.unreachable = run.transport.backend.unreachable(host_timing.status);
+            timing.hosts.insert(host.clone(), host_timing);
+        }
+        let durations = timing.durations();
+        timing.p50 = percentile(&durations, 50);
+        timing.p90 = percentile(&durations, 90);
+        timing.p99 = percentile(&durations, 99);
+        timing.max = durations.last().copied();
+        timing
+    }
+
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        write_json(dir, "timing.json", self)
+    }
+
+    /// Durations of the hosts which finished, shortest first
+    fn durations(&self) -> Vec<u64> {
+        let mut durations: Vec<u64> = self.hosts.values().filter_map(|h| h.duration).collect();
+        durations.sort();
+        durations
+    }
+
+    /// A summary and histogram of host durations, for `bdsh stats`
+    pub fn report(&self) -> String {
+        let durations = self.durations();
+        let mut out = format!("{} hosts, {} finished\n", self.hosts.len(), durations.len());
+        let (Some(&min), Some(max)) = (durations.first(), self.max) else {
+            return out;
+        };
+        let secs = |n: Option<u64>| n.map(|n| format!("{}s", n)).unwrap_or_default();
+        out.push_str(&format!(
+            "p50 {}  p90 {}  p99 {}  max {}\n\n",
+            secs(self.p50),
+            secs(self.p90),
+            secs(self.p99),
+            secs(self.max)
+        ));
+        let width = (max - min) / HISTOGRAM_BUCKETS + 1;
+        let mut low = min;
+        while low <= max {
+            let count = durations
+                .iter()
+                .filter(|&&d| d >= low && d < low + width)
+                .count();
+            let range = match width {
+                1 => format!("{}s", low),
+                _ => format!("{}s-{}s", low, low + width - 1),
+            };
+            out.push_str(&format!(
+                "{:>12} {:>5} {}\n",
+                range,
+                count,
+                "#".repeat(count)
+            ));
+            low += width;
+        }
+        out
+    }
+}
+
+const HISTOGRAM_BUCKETS: u64 = 10;
+
+/// Prometheus metrics for a run which took `elapsed` and ended at unix time
+/// `ended`, in the text format node_exporter's textfile collector reads
+pub fn metrics(timing: &Timing, elapsed: Duration, ended: u64) -> String {
+    let failed = timing
+        .hosts
+        .values()
+        .filter(|host| host.status != Some(0))
+        .count();
+    let mut out = String::new();
+    let mut metric = |name: &str, help: &str, value: String| {
+        out.push_str(&format!(
+            "# HELP bdsh_{name} {help}\n# TYPE bdsh_{name} gauge\nbdsh_{name} {value}\n"
+        ));
+    };
+    metric(
+        "hosts_total",
+        "Hosts in the last run.",
+        timing.hosts.len().to_string(),
+    );
+    metric(
+        "hosts_failed",
+        "Hosts in the last run which exited nonzero or didn't finish.",
+        failed.to_string(),
+    );
+    metric(
+        "run_duration_seconds",
+        "How long the last run took.",
+        format!("{:.3}", elapsed.as_secs_f64()),
+    );
+    metric(
+        "last_run_timestamp_seconds",
+        "When the last run ended, in unix time.",
+        ended.to_string(),
+    );
+    out
+}
+
+/// Replace `path` with `text` in one step, so a collector reading it never
+/// sees half a file
+pub fn write_metrics(path: &Path, text: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(tmp, path)
+}
+
+/// The nearest rank `pct` percentile of sorted `values`
+fn percentile(values: &[u64], pct: usize) -> Option<u64> {
+    if values.is_empty() {
+        return None;
+    }
+    let rank = (values.len() * pct).div_ceil(100).max(1);
+    Some(values[rank - 1])
+}
+
+/// Write `value` as json to `name` in `dir`, replacing any existing file
+/// atomically so readers never see a partial file
+fn write_json(dir: &Path, name: &str, value: &impl Serialize) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(value).map_err(io::Error::other)?;
+    let tmp = dir.join(format!(".{}.tmp", name));
+    fs::write(&tmp, json + "\n")?;
+    fs::rename(tmp, dir.join(name))
+}
+
+/// Parse a duration such as `90s`, `15m` or `2h`; a bare number is seconds
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, unit) = text.split_at(text.trim_end_matches(char::is_alphabetic).len());
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("expected a duration like 90s, 15m or 2h, found '{}'", text))?;
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("unknown unit '{}', expected s, m or h", unit)),
+    };
+    let secs = number
+        .checked_mul(scale)
+        .ok_or_else(|| format!("duration '{}' is too long", text))?;
+    Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("2d").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("99999999999999999h").is_err());
+    }
+
+    #[test]
+    fn test_read_log() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("bdsh-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut gz = GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(b"hello\n").unwrap();
+        fs::write(dir.join(COMPRESSED_LOG), gz.finish().unwrap()).unwrap();
+        assert!(has_log(&dir));
+        assert_eq!(read_log(&dir).unwrap(), "hello\n");
+
+        fs::write(dir.join(LOG), "plain\n").unwrap();
+        assert_eq!(read_log(&dir).unwrap(), "plain\n");
+
+        fs::write(dir.join(LOG), b"ok\xff\n").unwrap();
+        assert_eq!(read_log(&dir).unwrap(), "ok\u{fffd}\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut timing = Timing::default();
+        for (host, status) in [("a", Some(0)), ("b", Some(1)), ("c", None)] {
+            let host_timing = HostTiming {
+                status,
+                ..Default::default()
+            };
+            timing.hosts.insert(host.into(), host_timing);
+        }
+        let text = metrics(&timing, Duration::from_millis(1500), 1700000000);
+        assert!(text.contains("# TYPE bdsh_hosts_total gauge\nbdsh_hosts_total 3\n"));
+        assert!(text.contains("\nbdsh_hosts_failed 2\n"));
+        assert!(text.contains("\nbdsh_run_duration_seconds 1.500\n"));
+        assert!(text.contains("\nbdsh_last_run_timestamp_seconds 1700000000\n"));
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<u64> = (1..=10).collect();
+        assert_eq!(percentile(&values, 50), Some(5));
+        assert_eq!(percentile(&values, 90), Some(9));
+        assert_eq!(percentile(&values, 99), Some(10));
+        assert_eq!(percentile(&[7], 50), Some(7));
+        assert_eq!(percentile(&[], 50), None);
+    }
+}
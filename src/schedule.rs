@@ -0,0 +1,158 @@
+use crate::hosts::{valid_tag, Host};
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+/// Parse one `--order` item, a tag with a leading `:`, e.g. `:db`
+pub fn parse_order_tag(item: &str) -> Result<String, String> {
+    match item.strip_prefix(':') {
+        Some(tag) if valid_tag(tag) => Ok(tag.to_string()),
+        _ => Err(format!("expected a tag like :db, found '{}'", item)),
+    }
+}
+
+/// The order hosts start in, from `--order-by`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OrderBy {
+    /// as listed in the host spec and hosts file
+    #[default]
+    File,
+    /// sorted by name
+    Name,
+    /// shuffled, e.g. to spread a rolling restart across failure domains
+    Random,
+}
+
+/// Put hosts in `order_by` order. Shuffling with the same `seed` always
+/// gives the same order, for the same hosts.
+pub fn sort_hosts(hosts: &mut [Host], order_by: OrderBy, seed: u64) {
+    match order_by {
+        OrderBy::File => {}
+        OrderBy::Name => hosts.sort_by(|a, b| a.name.cmp(&b.name)),
+        OrderBy::Random => hosts.shuffle(&mut ChaCha8Rng::seed_from_u64(seed)),
+    }
+}
+
+/// How many hosts may fail before a run stops, from `--max-failures`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaxFailures {
+    Count(usize),
+    /// a percentage of the run's hosts
+    Percent(usize),
+}
+
+impl MaxFailures {
+    /// Parse a count, e.g. `3`, or a percentage, e.g. `10%`
+    pub fn parse(text: &str) -> Result<MaxFailures, String> {
+        let bad = || {
+            format!(
+                "expected a count like 3 or a percentage like 10%, found '{}'",
+                text
+            )
+        };
+        match text.strip_suffix('%') {
+            Some(percent) => match percent.parse() {
+                Ok(percent @ 1..=100) => Ok(MaxFailures::Percent(percent)),
+                _ => Err(bad()),
+            },
+            None => match text.parse() {
+                Ok(count @ 1..) => Ok(MaxFailures::Count(count)),
+                _ => Err(bad()),
+            },
+        }
+    }
+
+    /// The number of failures, out of `hosts`, which stops the run; a
+    /// percentage rounds up, and is always at least one host
+    pub fn limit(&self, hosts: usize) -> usize {
+        match *self {
+            MaxFailures::Count(count) => count,
+            MaxFailures::Percent(percent) => (hosts * percent).div_ceil(100).max(1),
+        }
+    }
+}
+
+/// Split hosts into groups to run one after another: those with the first
+/// tag in `order`, then those with the second, and so on, with any left
+/// over last. Hosts keep their order within a group. Without an order,
+/// every host is in one group.
+pub fn groups(hosts: Vec<Host>, order: &[String]) -> Vec<Vec<Host>> {
+    let mut groups: Vec<Vec<Host>> = vec![vec![]; order.len() + 1];
+    for host in hosts {
+        let group = order
+            .iter()
+            .position(|tag| host.tags.contains(tag))
+            .unwrap_or(order.len());
+        groups[group].push(host);
+    }
+    groups.retain(|group| !group.is_empty());
+    groups
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_groups() {
+        let host = |name: &str, tags: &[&str]| Host {
+            name: name.into(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        };
+        let hosts = vec![
+            host("web1", &["web"]),
+            host("db1", &["db"]),
+            host("misc", &[]),
+            host("web2", &["web", "db"]),
+            host("app1", &["app"]),
+        ];
+        let order: Vec<String> = [":db", ":app", ":web", ":cache"]
+            .into_iter()
+            .map(|item| parse_order_tag(item).unwrap())
+            .collect();
+        let names: Vec<Vec<String>> = groups(hosts, &order)
+            .into_iter()
+            .map(|group| group.into_iter().map(|host| host.name).collect())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                vec!["db1", "web2"],
+                vec!["app1"],
+                vec!["web1"],
+                vec!["misc"]
+            ]
+        );
+        assert!(parse_order_tag("db").is_err());
+    }
+
+    #[test]
+    fn test_sort_hosts() {
+        let mut hosts: Vec<Host> = (1..=20)
+            .map(|n| Host {
+                name: format!("web{:02}", n),
+                ..Default::default()
+            })
+            .collect();
+        let listed = hosts.clone();
+        sort_hosts(&mut hosts, OrderBy::Random, 7);
+        assert_ne!(hosts, listed);
+        let shuffled = hosts.clone();
+        sort_hosts(&mut hosts, OrderBy::Name, 0);
+        assert_eq!(hosts, listed);
+        sort_hosts(&mut hosts, OrderBy::Random, 7);
+        assert_eq!(hosts, shuffled);
+    }
+
+    #[test]
+    fn test_max_failures() {
+        assert_eq!(MaxFailures::parse("3").unwrap().limit(100), 3);
+        assert_eq!(MaxFailures::parse("10%").unwrap().limit(25), 3);
+        assert_eq!(MaxFailures::parse("10%").unwrap().limit(2), 1);
+        assert!(MaxFailures::parse("0").is_err());
+        assert!(MaxFailures::parse("150%").is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use thiserror::Error;
+
+type Result<T> = std::result::Result<T, SecretError>;
+
+/// A secret asked for once and given to every host, such as an ssh key
+/// passphrase. It is never printed, not even by `{:?}`.
+pub struct Secret(String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+impl Secret {
+    /// Get the secret from the output of `secret_cmd`, e.g. a keychain
+    /// lookup, or without one by asking on the terminal
+    pub fn obtain(secret_cmd: Option<&str>, prompt: &str) -> Result<Secret> {
+        match secret_cmd {
+            Some(cmd) => from_command(cmd),
+            None => ask_hidden(prompt),
+        }
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+fn from_command(cmd: &str) -> Result<Secret> {
+    let output = Command::new("sh")
+        .args(["-c", cmd])
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| SecretError::Command(cmd.into(), err.to_string()))?;
+    if !output.status.success() {
+        return Err(SecretError::Command(
+            cmd.into(),
+            format!("exited with {}", output.status),
+        ));
+    }
+    let text = String::from_utf8(output.stdout)
+        .map_err(|_| SecretError::Command(cmd.into(), "output isn't utf-8".into()))?;
+    // a newline ends the secret, as it would when typed
+    let secret = text.lines().next().unwrap_or_default();
+    Ok(Secret(secret.into()))
+}
+
+/// Read a line from the terminal without echoing it
+fn ask_hidden(prompt: &str) -> Result<Secret> {
+    let tty = || File::options().read(true).write(true).open("/dev/tty");
+    let mut out = tty().map_err(SecretError::NoTerminal)?;
+    let stty = |arg: &str| -> io::Result<()> {
+        Command::new("stty").arg(arg).stdin(tty()?).status()?;
+        Ok(())
+    };
+    write!(out, "{}", prompt)?;
+    stty("-echo")?;
+    let mut line = String::new();
+    let read = BufReader::new(tty()?).read_line(&mut line);
+    stty("echo")?;
+    writeln!(out)?;
+    read?;
+    Ok(Secret(line.trim_end_matches(['\r', '\n']).into()))
+}
+
+/// Environment variable jobs get the secret in
+pub const SECRET_VAR: &str = "BDSH_SECRET";
+
+/// An `SSH_ASKPASS` program which answers ssh's passphrase and password
+/// prompts with `BDSH_SECRET`, and declines to confirm anything else, such
+/// as an unknown host key
+const ASKPASS: &str = r#"#!/bin/sh
+case "$1" in
+  *"(yes/no"*) exit 1 ;;
+esac
+printf '%s\n' "$BDSH_SECRET"
+"#;
+
+/// Write the askpass program into the output directory, returning its path
+pub fn write_askpass(dir: &Path) -> io::Result<PathBuf> {
+    let path = dir.join("askpass");
+    fs::write(&path, ASKPASS)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
+    Ok(path)
+}
+
+/// Environment for ssh to get `secret` from the askpass program at `askpass`
+pub fn askpass_env(secret: &Secret, askpass: &Path) -> Vec<(String, String)> {
+    let mut env = secret_env(secret);
+    env.push(("SSH_ASKPASS".into(), askpass.to_string_lossy().into()));
+    env.push(("SSH_ASKPASS_REQUIRE".into(), "force".into()));
+    env
+}
+
+/// Environment which gives jobs `secret`
+pub fn secret_env(secret: &Secret) -> Vec<(String, String)> {
+    vec![(SECRET_VAR.into(), secret.expose().into())]
+}
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("secret command '{0}' failed: {1}")]
+    Command(String, String),
+
+    #[error("no terminal to ask for a secret on: {0}")]
+    NoTerminal(io::Error),
+
+    #[error("unable to ask for a secret: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_askpass() {
+        let dir = std::env::temp_dir().join(format!("bdsh-askpass-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let askpass = write_askpass(&dir).unwrap();
+        let secret = from_command("printf 'hunter2\\nignored'").unwrap();
+        assert_eq!(format!("{:?}", secret), "Secret(..)");
+        let run = |prompt: &str| {
+            Command::new(&askpass)
+                .arg(prompt)
+                .envs(askpass_env(&secret, &askpass))
+                .output()
+                .unwrap()
+        };
+        let output = run("Enter passphrase for key '/home/me/.ssh/id_ed25519': ");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hunter2\n");
+        let output = run("Are you sure you want to continue connecting (yes/no/[fingerprint])? ");
+        assert!(!output.status.success());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
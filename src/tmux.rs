@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::io::Write;
 use std::io::{BufRead, BufReader};
 use std::process::{Command, Stdio};
@@ -12,25 +13,50 @@ pub struct Control {
     tmux: std::process::Child,
     stdin: std::process::ChildStdin,
     stdout: BufReader<std::process::ChildStdout>,
+
+    /// windows we created, by name, so actions target a window by its id
+    /// rather than a name or index the user may have reused
+    windows: HashMap<String, Window>,
 }
 
-#[derive(Debug)]
+/// A window we created, or a pane split off one, which is treated as a
+/// window of its own
+#[derive(Debug, Clone)]
 pub struct Window {
-    name: String,
     id: String,
+    /// the pane the window's command runs in, e.g. `%5`
+    pane: String,
+}
+
+impl Window {
+    /// tmux's id for the window, e.g. `@3`, which never changes or gets
+    /// reused. Panes share their window's id.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
 }
 
 impl Control {
     pub fn start_session(name: &str, command: Option<String>) -> Result<Control> {
-        let mut args = vec!["-C", "new-session", "-s", &name];
+        let mut args = vec!["new-session", "-s", name];
         let command: Option<&str> = command.as_deref();
         args.extend(command.iter());
+        Control::connect(name, &args)
+    }
+
+    /// Open a new control connection to an existing session
+    pub fn attach_session(name: &str) -> Result<Control> {
+        Control::connect(name, &["attach-session", "-t", &exact(name)])
+    }
+
+    fn connect(name: &str, args: &[&str]) -> Result<Control> {
         let mut tmux = Command::new("tmux")
+            .arg("-C")
             .args(args)
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
             .spawn()
-            .map_err(TmuxError::IoError)?;
+            .map_err(TmuxError::Io)?;
 
         let stdin = tmux.stdin.take().unwrap();
         let stdout = tmux.stdout.take().unwrap();
@@ -40,75 +66,240 @@ impl Control {
             tmux,
             stdin,
             stdout: BufReader::new(stdout),
+            windows: HashMap::new(),
         };
 
-        // now consume notifs until we see our session
+        // now consume notifs until we see our session, or tmux gives up
         loop {
-            let notif = c.consume_notification()?;
-            match notif {
+            match c.consume_notification()? {
                 Notification::SessionChanged(_, name) if name == c.name => break,
-                _ => continue, //print!("skipping notif: {:?}\n", notif),
+                // the reply to the command we started with, which fails
+                // if e.g. the session is already there
+                Notification::Begin(number) => {
+                    c.reply_lines(number)?;
+                }
+                Notification::Exit(reason) => {
+                    return Err(TmuxError::ControlLost {
+                        name: c.name.clone(),
+                        msg: reason.unwrap_or_else(|| "control tmux exited".into()),
+                    })
+                }
+                _ => continue,
             }
         }
         Ok(c)
     }
 
-    pub fn new_window(&mut self, name: &str, command: Option<&str>) -> Result<Window> {
-        // use a convention where we send -P -F '@#{window_name} #{window_id}'
-        // to let us get the window id
-        let mut parts = vec![
-            "new-window",
-            "-d",
-            "-P",
-            "-F",
-            "'@ #{window_name} #{window_id}'",
-            "-n",
-            name,
-        ];
-        parts.extend(command.iter());
+    /// Make sure the control tmux is still running. If it has died, try to
+    /// reattach to the session, which outlives it, and fail if we can't.
+    fn ensure_connected(&mut self) -> Result<()> {
+        let status = match self.tmux.try_wait().map_err(TmuxError::Io)? {
+            None => return Ok(()),
+            Some(status) => status,
+        };
+        match Control::attach_session(&self.name) {
+            Ok(mut control) => {
+                control.windows = std::mem::take(&mut self.windows);
+                *self = control;
+                Ok(())
+            }
+            Err(err) => Err(TmuxError::ControlLost {
+                name: self.name.clone(),
+                msg: format!(
+                    "control tmux exited with {}, and reattaching failed: {}",
+                    status, err
+                ),
+            }),
+        }
+    }
+
+    /// Create a window named `name`, which must not be one we already made,
+    /// with `env` added to its environment
+    pub fn new_window(
+        &mut self,
+        name: &str,
+        command: Option<&str>,
+        env: &[(String, String)],
+    ) -> Result<Window> {
+        let name_arg = quote(name);
+        self.create(&["new-window", "-d", "-n", &name_arg], name, command, env)
+    }
+
+    /// Split a new pane off `window`, known as `name` as a window would be,
+    /// with `env` added to its environment. The pane is titled `name`.
+    pub fn split_window(
+        &mut self,
+        window: &Window,
+        name: &str,
+        command: Option<&str>,
+        env: &[(String, String)],
+    ) -> Result<Window> {
+        let target = quote(window.id());
+        let pane = self.create(&["split-window", "-d", "-t", &target], name, command, env)?;
+        self.set_title(&pane, name)?;
+        Ok(pane)
+    }
+
+    /// Title the pane a window's command runs in, shown in its border if
+    /// `pane-border-status` is on
+    pub fn set_title(&mut self, window: &Window, title: &str) -> Result<()> {
+        let pane = window.pane.clone();
+        self.command(&["select-pane", "-t", &pane, "-T", title])
+    }
+
+    /// Run `args`, a tmux command which makes a pane, with `command` and
+    /// `env`, and remember the pane as `name`
+    fn create(
+        &mut self,
+        args: &[&str],
+        name: &str,
+        command: Option<&str>,
+        env: &[(String, String)],
+    ) -> Result<Window> {
+        if self.windows.contains_key(name) {
+            return Err(TmuxError::DuplicateWindow(name.into()));
+        }
+        // -P prints the new window and pane ids, in the command's reply
+        let mut parts = args.to_vec();
+        parts.extend(["-P", "-F", "'#{window_id} #{pane_id}'"]);
+        let env_args: Vec<String> = env
+            .iter()
+            .map(|(key, value)| quote(&format!("{}={}", key, value)))
+            .collect();
+        for arg in &env_args {
+            parts.extend(["-e", arg]);
+        }
+        let command_arg = command.map(quote);
+        parts.extend(command_arg.as_deref());
         let line = parts.join(" ");
 
         self.send(&format!("{}\n", line))?;
+        let ids = self.reply()?.join(" ");
+        let (id, pane) = ids
+            .split_once(' ')
+            .ok_or_else(|| TmuxError::NotifParse(format!("no window and pane id in '{}'", ids)))?;
+        let window = Window {
+            id: id.into(),
+            pane: pane.into(),
+        };
+        self.windows.insert(name.into(), window.clone());
+        Ok(window)
+    }
+
+    /// Kill a window we created, or a pane split off one, and whatever is
+    /// running in it
+    pub fn kill_window(&mut self, window: &Window) -> Result<()> {
+        let pane = window.pane.clone();
+        self.command(&["kill-pane", "-t", &pane])
+    }
+
+    /// Type `text` into a window we created, then press enter
+    pub fn send_line(&mut self, window: &Window, text: &str) -> Result<()> {
+        let pane = window.pane.clone();
+        self.command(&["send-keys", "-t", &pane, "-l", text])?;
+        self.command(&["send-keys", "-t", &pane, "Enter"])
+    }
+
+    /// Arrange a window's panes in one of tmux's layouts, e.g. `tiled`
+    pub fn select_layout(&mut self, window: &Window, layout: &str) -> Result<()> {
+        let id = window.id.clone();
+        self.command(&["select-layout", "-t", &id, layout])
+    }
+
+    /// Rename a window, leaving it known to us by its old name
+    pub fn rename_window(&mut self, window: &Window, name: &str) -> Result<()> {
+        let id = window.id.clone();
+        self.command(&["rename-window", "-t", &id, name])
+    }
+
+    /// Set an option of the window with id `window`, e.g.
+    /// `pane-border-status`
+    pub fn set_window_option(&mut self, window: &str, option: &str, value: &str) -> Result<()> {
+        self.command(&["set-option", "-w", "-t", window, option, value])
+    }
+
+    /// A window we created, by name
+    pub fn window(&self, name: &str) -> Option<&Window> {
+        self.windows.get(name)
+    }
 
-        // now consume notifs until we get our window id
-        let mut id = String::new();
+    /// Set a session option, e.g. `visual-bell`
+    pub fn set_option(&mut self, option: &str, value: &str) -> Result<()> {
+        // set-option only takes the exact form with a trailing colon
+        let name = format!("{}:", exact(&self.name));
+        self.command(&["set-option", "-t", &name, option, value])
+    }
+
+    /// Run a tmux command, and wait for its reply so it isn't taken for the
+    /// next command's
+    fn command(&mut self, args: &[&str]) -> Result<()> {
+        let line: Vec<String> = args.iter().map(|arg| quote(arg)).collect();
+        self.send(&format!("{}\n", line.join(" ")))?;
+        self.reply()?;
+        Ok(())
+    }
+
+    /// Wait for the reply to the command just sent, handling notifications
+    /// which come first, and return its output, or its error as an error
+    fn reply(&mut self) -> Result<Vec<String>> {
         loop {
-            let n = self.consume_notification()?;
-            match n {
-                Notification::End => break,
-                Notification::Output(data) => {
-                    let (_, window_id) = data.split_once(" ").unwrap();
-                    id.push_str(window_id);
+            match self.consume_notification()? {
+                Notification::Begin(number) => return self.reply_lines(number),
+                Notification::Exit(reason) => {
+                    return Err(TmuxError::ControlLost {
+                        name: self.name.clone(),
+                        msg: reason.unwrap_or_else(|| "control tmux exited".into()),
+                    })
                 }
                 _ => continue,
             }
         }
-        Ok(Window {
-            name: name.into(),
-            id,
-        })
+    }
+
+    /// The lines of the reply to command `number` after its %begin, up to
+    /// its %end or %error
+    fn reply_lines(&mut self, number: u64) -> Result<Vec<String>> {
+        let mut lines = vec![];
+        loop {
+            let mut buf = String::new();
+            if self.stdout.read_line(&mut buf).map_err(TmuxError::Io)? == 0 {
+                return Err(TmuxError::NotifParse("reply cut short".into()));
+            }
+            let line = buf.trim_end_matches('\n');
+            // output could look like %end, but not with the same number
+            match line.parse::<Notification>() {
+                Ok(Notification::End(n)) if n == number => return Ok(lines),
+                Ok(Notification::Error(n)) if n == number => {
+                    return Err(TmuxError::Command(lines.join("\n")))
+                }
+                _ => lines.push(line.into()),
+            }
+        }
     }
 
     fn consume_notification(&mut self) -> Result<Notification> {
         let mut buf = String::new();
-        self.stdout
-            .read_line(&mut buf)
-            .map_err(TmuxError::IoError)?;
+        if self.stdout.read_line(&mut buf).map_err(TmuxError::Io)? == 0 {
+            return Ok(Notification::Exit(None));
+        }
         let n = buf.parse()?;
-        println!("notif\t{:?}", n);
+        if let Notification::WindowClose(id) | Notification::UnlinkedWindowClose(id) = &n {
+            // it's gone, so nothing more can be done to it
+            self.windows.retain(|_, window| &window.id != id);
+        }
         Ok(n)
     }
 
     pub fn kill(&mut self) -> Result<()> {
         self.tmux.kill().map_err(|err| -> TmuxError {
-            TmuxError::ChildError {
+            TmuxError::Child {
                 msg: format!("unable to kill {}", err),
                 source: err,
             }
         })?;
 
         self.tmux.wait().map_err(|err| -> TmuxError {
-            TmuxError::ChildError {
+            TmuxError::Child {
                 msg: format!("unable to wait for child: {}", err),
                 source: err,
             }
@@ -117,83 +308,278 @@ impl Control {
     }
 
     pub fn send(&mut self, command: &str) -> Result<()> {
-        self.stdin
-            .write_all(command.as_bytes())
-            .map_err(TmuxError::IoError)?;
+        self.ensure_connected()?;
+        if let Err(err) = self.stdin.write_all(command.as_bytes()) {
+            if err.kind() != std::io::ErrorKind::BrokenPipe {
+                return Err(TmuxError::Io(err));
+            }
+            // it died between the check and the write, wait for it and retry
+            let _ = self.tmux.wait();
+            self.ensure_connected()?;
+            self.stdin
+                .write_all(command.as_bytes())
+                .map_err(TmuxError::Io)?;
+        }
         Ok(())
     }
 }
 
+/// Check a session name given by the user is one tmux will take as is,
+/// since it turns `.` and `:` into `_`
+pub fn parse_session_name(name: &str) -> std::result::Result<String, String> {
+    if name.is_empty() || name.contains(['.', ':']) {
+        return Err(format!(
+            "'{}' can't be a tmux session name, which must be non-empty without . or :",
+            name
+        ));
+    }
+    Ok(name.into())
+}
+
+/// Target the session named exactly `name`, where a bare name would also
+/// match sessions it is a prefix of
+pub fn exact(name: &str) -> String {
+    format!("={}", name)
+}
+
+/// Quote an argument for the tmux command parser, which follows sh rules for
+/// single quotes.
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
 #[derive(Error, Debug)]
 pub enum TmuxError {
     #[error("problem with communicating with child tmux: {0}")]
-    IoError(#[from] std::io::Error),
+    Io(#[from] std::io::Error),
 
     #[error("problem with child tmux: {msg}")]
-    ChildError { msg: String, source: std::io::Error },
+    Child { msg: String, source: std::io::Error },
+
+    #[error("lost control connection to tmux session {name}: {msg}")]
+    ControlLost { name: String, msg: String },
+
+    #[error("window {0} already exists in this session")]
+    DuplicateWindow(String),
 
     #[error("notification parse error: {0}")]
-    NotifParseError(String),
+    NotifParse(String),
+
+    #[error("tmux command failed: {0}")]
+    Command(String),
+}
+
+impl TmuxError {
+    /// Whether tmux refused a command because its target window or pane is
+    /// gone, e.g. as its command finished
+    pub fn is_gone(&self) -> bool {
+        matches!(self, TmuxError::Command(msg) if msg.starts_with("can't find"))
+    }
 }
 
+/// A line from tmux in control mode. Replies to commands come between
+/// `%begin` and `%end` (or `%error`), and the rest are notifications of what
+/// happens in the session.
 #[derive(Debug, PartialEq)]
 enum Notification {
+    /// start of a command's reply, with the command's number
+    Begin(u64),
+    /// end of a command's reply
+    End(u64),
+    /// end of the reply of a command which failed
+    Error(u64),
+    /// output from the pane with this id
+    Output {
+        pane: String,
+        data: Vec<u8>,
+    },
+    /// the control client is exiting, perhaps with why
+    Exit(Option<String>),
+    /// a window's panes were rearranged
+    LayoutChange {
+        window: String,
+        layout: String,
+    },
+    WindowAdd(String),
+    WindowClose(String),
+    /// a window not in our session closed
+    UnlinkedWindowClose(String),
+    WindowRenamed {
+        window: String,
+        name: String,
+    },
+    /// the active pane of a window changed
+    WindowPaneChanged {
+        window: String,
+        pane: String,
+    },
+    /// we're attached to another session, with id and name
     SessionChanged(String, String),
+    SessionRenamed(String),
+    /// the current window of a session changed
+    SessionWindowChanged {
+        session: String,
+        window: String,
+    },
+    /// a session was created or destroyed
+    SessionsChanged,
+    PaneModeChanged(String),
     Other(String, Option<String>),
-    Begin,
-    Output(String),
-    End,
 }
 
 impl FromStr for Notification {
     type Err = TmuxError;
 
     fn from_str(data: &str) -> Result<Notification> {
-        if data.is_empty() || !(data.starts_with(r"%") || data.starts_with(r"@")) {
-            return Err(TmuxError::NotifParseError(format!(
-                "parse error: '{}'",
-                data
-            )));
+        if !data.starts_with('%') {
+            return Err(TmuxError::NotifParse(format!("parse error: '{}'", data)));
         }
-        let data = data.trim_end_matches("\n"); // strip trailing newline
-        let (notif_type, notif_data) = match data.split_once(" ") {
-            Some((notif_type, notif_data)) => (notif_type, Some(notif_data.into())),
+        let data = data.trim_end_matches('\n'); // strip trailing newline
+        let (notif_type, notif_data) = match data.split_once(' ') {
+            Some((notif_type, notif_data)) => (notif_type, Some(notif_data)),
             None => (data, None),
         };
+        let args = |n: usize| -> Result<Vec<&str>> {
+            let args: Vec<&str> = notif_data.unwrap_or_default().splitn(n, ' ').collect();
+            if args.len() < n || args.iter().any(|arg| arg.is_empty()) {
+                return Err(TmuxError::NotifParse(format!(
+                    "{} notification missing data",
+                    notif_type
+                )));
+            }
+            Ok(args)
+        };
+        // %begin, %end and %error have a time, command number, and flags
+        let number = || -> Result<u64> {
+            args(3)?[1]
+                .parse()
+                .map_err(|_| TmuxError::NotifParse(format!("bad command number in '{}'", data)))
+        };
 
-        match notif_type {
-            "%session-changed" => Notification::session_changed(notif_data),
-            "%begin" => Ok(Notification::Begin),
-            "%end" => Ok(Notification::End),
-            "@" => Ok(Notification::Output(notif_data.unwrap_or_default())),
-            _ => Ok(Notification::Other(notif_type.into(), notif_data)),
-        }
+        Ok(match notif_type {
+            "%begin" => Notification::Begin(number()?),
+            "%end" => Notification::End(number()?),
+            "%error" => Notification::Error(number()?),
+            "%output" => {
+                let args = args(2)?;
+                Notification::Output {
+                    pane: args[0].into(),
+                    data: unescape(args[1]),
+                }
+            }
+            "%exit" => Notification::Exit(notif_data.map(Into::into)),
+            "%layout-change" => {
+                let args = args(2)?;
+                let layout = args[1].split(' ').next().unwrap_or_default();
+                Notification::LayoutChange {
+                    window: args[0].into(),
+                    layout: layout.into(),
+                }
+            }
+            "%window-add" => Notification::WindowAdd(args(1)?[0].into()),
+            "%window-close" => Notification::WindowClose(args(1)?[0].into()),
+            "%unlinked-window-close" => Notification::UnlinkedWindowClose(args(1)?[0].into()),
+            "%window-renamed" => {
+                let args = args(2)?;
+                Notification::WindowRenamed {
+                    window: args[0].into(),
+                    name: args[1].into(),
+                }
+            }
+            "%window-pane-changed" => {
+                let args = args(2)?;
+                Notification::WindowPaneChanged {
+                    window: args[0].into(),
+                    pane: args[1].into(),
+                }
+            }
+            "%session-changed" => {
+                let args = args(2)?;
+                Notification::SessionChanged(args[0].into(), args[1].into())
+            }
+            "%session-renamed" => Notification::SessionRenamed(args(1)?[0].into()),
+            "%session-window-changed" => {
+                let args = args(2)?;
+                Notification::SessionWindowChanged {
+                    session: args[0].into(),
+                    window: args[1].into(),
+                }
+            }
+            "%sessions-changed" => Notification::SessionsChanged,
+            "%pane-mode-changed" => Notification::PaneModeChanged(args(1)?[0].into()),
+            _ => Notification::Other(notif_type.into(), notif_data.map(Into::into)),
+        })
     }
 }
 
-impl Notification {
-    fn session_changed(data: Option<String>) -> Result<Notification> {
-        let data = data.ok_or_else(|| {
-            TmuxError::NotifParseError("%session-changed notification missing data".into())
-        })?;
-        let (session_number, session_name) = match data.split_once(" ") {
-            Some((session_number, session_name)) => (session_number, session_name),
-            None => {
-                return Err(TmuxError::NotifParseError(
-                    "missing session name in %session-changed".into(),
-                ))
+/// Undo tmux's escaping of %output, where characters below space and `\`
+/// are sent as a backslash and three octal digits
+fn unescape(data: &str) -> Vec<u8> {
+    let bytes = data.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
             }
-        };
-        Ok(Notification::SessionChanged(
-            session_number.into(),
-            session_name.into(),
-        ))
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
     }
+    out
 }
 
+#[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_parse_session_name() {
+        assert_eq!(parse_session_name("deploy-web").unwrap(), "deploy-web");
+        assert!(parse_session_name("deploy.web").is_err());
+        assert!(parse_session_name("").is_err());
+    }
+
+    #[test]
+    fn test_notification_framing() {
+        let parse = |line: &str| line.parse::<Notification>().unwrap();
+        assert_eq!(parse("%begin 1700000000 42 1\n"), Notification::Begin(42));
+        assert_eq!(parse("%error 1700000000 42 1"), Notification::Error(42));
+        assert_eq!(
+            parse(r"%output %3 ok\015\012"),
+            Notification::Output {
+                pane: "%3".into(),
+                data: b"ok\r\n".to_vec()
+            }
+        );
+        assert_eq!(
+            parse("%layout-change @2 b25f,80x24,0,0,2 b25f,80x24,0,0,2 *"),
+            Notification::LayoutChange {
+                window: "@2".into(),
+                layout: "b25f,80x24,0,0,2".into()
+            }
+        );
+        assert_eq!(
+            parse("%window-renamed @2 web 1"),
+            Notification::WindowRenamed {
+                window: "@2".into(),
+                name: "web 1".into()
+            }
+        );
+        assert_eq!(parse("%exit"), Notification::Exit(None));
+        assert!(TmuxError::Command("can't find pane: %7".into()).is_gone());
+        assert!(!TmuxError::Command("unknown command: x".into()).is_gone());
+        assert!("%window-close".parse::<Notification>().is_err());
+    }
+
     #[test]
     fn test_notification_parse() {
         let notif = "%session-changed 1 m0001\n"